@@ -43,6 +43,56 @@ impl Grid {
         }
     }
 
+    /// Bilinear interpolation of the elevation, clamped to the raster extent. A raster of a
+    /// single row or column is interpolated along the other direction only.
+    pub fn elevation_at(&self, x: f64, y: f64) -> f64 {
+        let fx = ((x - self.x_min) / self.x_res).clamp(0.0, self.nx.saturating_sub(1) as f64);
+        let fy = ((y - self.y_min) / self.y_res).clamp(0.0, self.ny.saturating_sub(1) as f64);
+        let col = (fx.floor() as usize).min(self.nx.saturating_sub(2));
+        let row = (fy.floor() as usize).min(self.ny.saturating_sub(2));
+        let next_col = (col + 1).min(self.nx - 1);
+        let next_row = (row + 1).min(self.ny - 1);
+        let (tx, ty) = (fx - col as f64, fy - row as f64);
+
+        (1.0 - tx) * (1.0 - ty) * self.z(col, row)
+            + tx * (1.0 - ty) * self.z(next_col, row)
+            + (1.0 - tx) * ty * self.z(col, next_row)
+            + tx * ty * self.z(next_col, next_row)
+    }
+
+    /// Keeps every `factor`-th raster node in each direction, so coarse nodes are also fine nodes
+    pub fn coarsen(&self, factor: usize) -> Grid {
+        let factor = factor.max(1);
+        let nx = (self.nx - 1) / factor + 1;
+        let ny = (self.ny - 1) / factor + 1;
+        let elevations = Array2::from_shape_fn((nx, ny), |(col, row)| {
+            self.elevations[[col * factor, row * factor]]
+        });
+
+        let (z_min, z_max) = elevations
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &val| {
+                (min.min(val), max.max(val))
+            });
+
+        let x_res = self.x_res * factor as f64;
+        let y_res = self.y_res * factor as f64;
+
+        Grid {
+            elevations,
+            x_min: self.x_min,
+            y_min: self.y_min,
+            x_max: self.x_min + x_res * (nx - 1) as f64,
+            y_max: self.y_min + y_res * (ny - 1) as f64,
+            x_res,
+            y_res,
+            z_min,
+            z_max,
+            nx,
+            ny,
+        }
+    }
+
     pub fn triangulate(&self) -> Vec<Triangle> {
         let mut triangles = Vec::new();
         let (cols, rows) = self.elevations.dim();
@@ -306,4 +356,31 @@ mod tests {
         let created = grid.make_boundary(stl_path, max_height);
         assert!(created.is_ok());
     }
+
+    #[test]
+    fn test_elevation_at_single_row() {
+        let grid = Grid {
+            elevations: Array2::from_shape_fn((4, 1), |(col, _row)| col as f64 * 10.0),
+            x_min: 0.0,
+            y_min: 0.0,
+            x_max: 3.0,
+            y_max: 0.0,
+            x_res: 1.0,
+            y_res: 1.0,
+            z_min: 0.0,
+            z_max: 30.0,
+            nx: 4,
+            ny: 1,
+        };
+        assert_eq!(grid.elevation_at(1.5, 0.0), 15.0);
+        assert_eq!(grid.elevation_at(5.0, 2.0), 30.0);
+
+        let column = Grid {
+            elevations: grid.elevations.t().to_owned(),
+            nx: 1,
+            ny: 4,
+            ..grid
+        };
+        assert_eq!(column.elevation_at(0.0, 0.5), 5.0);
+    }
 }
@@ -0,0 +1,546 @@
+use crate::sparse_system::linear_solver::{
+    LinearSolverSettings, PreconditionerKind, SolverMethod, Tolerance,
+};
+use crate::sparse_system::sparse_matrix::SparseMatrix;
+use crate::sparse_system::sparse_system::{SolverResult, SparseSystem};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variable {
+    Velocity,
+    Pressure,
+    Temperature,
+    Density,
+    Energy,
+}
+
+#[derive(Clone, Debug)]
+pub struct RelaxationFactors {
+    pub velocity: f64,
+    pub pressure: f64,
+    pub temperature: f64,
+    pub density: f64,
+    pub energy: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct PseudoTransient {
+    pub cfl: f64,
+    pub min_time_step: f64,
+    pub max_time_step: f64,
+}
+
+/// Linear solver used for the system of each equation
+#[derive(Clone, Debug)]
+pub struct LinearSolvers {
+    pub velocity: LinearSolverSettings,
+    pub pressure: LinearSolverSettings,
+    pub temperature: LinearSolverSettings,
+    pub density: LinearSolverSettings,
+    pub energy: LinearSolverSettings,
+}
+
+#[derive(Clone, Debug)]
+pub struct SolverControls {
+    pub relaxation: RelaxationFactors,
+    pub pseudo_transient: Option<PseudoTransient>,
+    pub linear_solvers: LinearSolvers,
+}
+
+impl Variable {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Variable::Velocity => "velocity",
+            Variable::Pressure => "pressure",
+            Variable::Temperature => "temperature",
+            Variable::Density => "density",
+            Variable::Energy => "energy",
+        }
+    }
+}
+
+impl RelaxationFactors {
+    pub fn new() -> RelaxationFactors {
+        // Usual SIMPLE values for steady incompressible-like flows
+        RelaxationFactors {
+            velocity: 0.7,
+            pressure: 0.3,
+            temperature: 0.9,
+            density: 1.0,
+            energy: 0.9,
+        }
+    }
+
+    pub fn factor(&self, variable: Variable) -> f64 {
+        match variable {
+            Variable::Velocity => self.velocity,
+            Variable::Pressure => self.pressure,
+            Variable::Temperature => self.temperature,
+            Variable::Density => self.density,
+            Variable::Energy => self.energy,
+        }
+    }
+
+    pub fn set_factor(&mut self, variable: Variable, value: f64) {
+        match variable {
+            Variable::Velocity => self.velocity = value,
+            Variable::Pressure => self.pressure = value,
+            Variable::Temperature => self.temperature = value,
+            Variable::Density => self.density = value,
+            Variable::Energy => self.energy = value,
+        }
+    }
+}
+
+impl LinearSolvers {
+    pub fn new() -> LinearSolvers {
+        // The pressure correction is symmetric positive definite, transport equations are not
+        let transport = LinearSolverSettings::new(SolverMethod::BiCgStab, PreconditionerKind::Ilu0);
+        LinearSolvers {
+            velocity: transport.clone(),
+            pressure: LinearSolverSettings::new(
+                SolverMethod::ConjugateGradient,
+                PreconditionerKind::Ic0,
+            ),
+            temperature: transport.clone(),
+            density: transport.clone(),
+            energy: transport,
+        }
+    }
+
+    pub fn settings(&self, variable: Variable) -> &LinearSolverSettings {
+        match variable {
+            Variable::Velocity => &self.velocity,
+            Variable::Pressure => &self.pressure,
+            Variable::Temperature => &self.temperature,
+            Variable::Density => &self.density,
+            Variable::Energy => &self.energy,
+        }
+    }
+
+    /// Solves the system of `variable` with its configured solver, from `initial_guess` or
+    /// zero
+    // Called by the flow solver for each equation, which main does not run yet
+    #[allow(dead_code)]
+    pub fn solve(
+        &self,
+        variable: Variable,
+        system: &SparseSystem<SparseMatrix>,
+        initial_guess: Option<Vec<f64>>,
+    ) -> SolverResult {
+        let settings = self.settings(variable);
+        let mut config = settings.config();
+        config.initial_guess = initial_guess;
+        settings.solver().solve(system, &config)
+    }
+
+    pub fn settings_mut(&mut self, variable: Variable) -> &mut LinearSolverSettings {
+        match variable {
+            Variable::Velocity => &mut self.velocity,
+            Variable::Pressure => &mut self.pressure,
+            Variable::Temperature => &mut self.temperature,
+            Variable::Density => &mut self.density,
+            Variable::Energy => &mut self.energy,
+        }
+    }
+}
+
+impl PseudoTransient {
+    pub fn new(cfl: f64) -> PseudoTransient {
+        PseudoTransient {
+            cfl,
+            min_time_step: 0.0,
+            max_time_step: f64::INFINITY,
+        }
+    }
+}
+
+impl SolverControls {
+    const VARIABLES: [Variable; 5] = [
+        Variable::Velocity,
+        Variable::Pressure,
+        Variable::Temperature,
+        Variable::Density,
+        Variable::Energy,
+    ];
+
+    pub fn new() -> SolverControls {
+        SolverControls {
+            relaxation: RelaxationFactors::new(),
+            pseudo_transient: None,
+            linear_solvers: LinearSolvers::new(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for variable in SolverControls::VARIABLES {
+            let alpha = self.relaxation.factor(variable);
+            if !(alpha > 0.0 && alpha <= 1.0) {
+                return Err(format!(
+                    "Relaxation factor for {} must be in (0, 1], got {}",
+                    variable.name(),
+                    alpha
+                ));
+            }
+        }
+
+        for variable in SolverControls::VARIABLES {
+            let settings = self.linear_solvers.settings(variable);
+            let tol = match settings.tolerance {
+                Tolerance::Absolute(tol) | Tolerance::Relative(tol) => tol,
+            };
+            if tol <= 0.0 || settings.max_iters == 0 || settings.restart == 0 {
+                return Err(format!(
+                    "Linear solver of {} needs a positive tolerance, max_iters and restart",
+                    variable.name()
+                ));
+            }
+            if !(settings.omega > 0.0 && settings.omega < 2.0) {
+                return Err(format!(
+                    "SOR factor for {} must be in (0, 2), got {}",
+                    variable.name(),
+                    settings.omega
+                ));
+            }
+        }
+
+        if let Some(pseudo) = &self.pseudo_transient {
+            if pseudo.cfl <= 0.0 {
+                return Err(format!(
+                    "Pseudo-time CFL must be positive, got {}",
+                    pseudo.cfl
+                ));
+            }
+            if pseudo.min_time_step < 0.0 || pseudo.min_time_step > pseudo.max_time_step {
+                return Err(format!(
+                    "Invalid pseudo-time step bounds [{}, {}]",
+                    pseudo.min_time_step, pseudo.max_time_step
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, file_path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        let file = File::create(file_path)?;
+        let mut writer = BufWriter::new(file);
+
+        for variable in SolverControls::VARIABLES {
+            writeln!(
+                writer,
+                "relaxation.{} {}",
+                variable.name(),
+                self.relaxation.factor(variable)
+            )?;
+        }
+
+        for variable in SolverControls::VARIABLES {
+            let settings = self.linear_solvers.settings(variable);
+            let prefix = format!("linear_solver.{}", variable.name());
+            writeln!(writer, "{}.method {}", prefix, settings.method.name())?;
+            writeln!(
+                writer,
+                "{}.preconditioner {}",
+                prefix,
+                settings.preconditioner.name()
+            )?;
+            match settings.tolerance {
+                Tolerance::Absolute(tol) => {
+                    writeln!(writer, "{}.absolute_tolerance {}", prefix, tol)?
+                }
+                Tolerance::Relative(tol) => {
+                    writeln!(writer, "{}.relative_tolerance {}", prefix, tol)?
+                }
+            }
+            writeln!(writer, "{}.max_iters {}", prefix, settings.max_iters)?;
+            writeln!(writer, "{}.restart {}", prefix, settings.restart)?;
+            writeln!(writer, "{}.omega {}", prefix, settings.omega)?;
+        }
+
+        if let Some(pseudo) = &self.pseudo_transient {
+            writeln!(writer, "pseudo_transient.cfl {}", pseudo.cfl)?;
+            writeln!(
+                writer,
+                "pseudo_transient.min_time_step {}",
+                pseudo.min_time_step
+            )?;
+            writeln!(
+                writer,
+                "pseudo_transient.max_time_step {}",
+                pseudo.max_time_step
+            )?;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn load(file_path: impl AsRef<Path>) -> Result<SolverControls, Box<dyn std::error::Error>> {
+        let file = File::open(file_path)?;
+        let reader = BufReader::new(file);
+
+        let mut controls = SolverControls::new();
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 2 {
+                return Err(format!("Line {}: expected '<key> <value>'", line_number + 1).into());
+            }
+
+            let key = parts[0];
+
+            if let Some(rest) = key.strip_prefix("linear_solver.") {
+                let (name, setting) = rest.split_once('.').unwrap_or((rest, ""));
+                let variable = SolverControls::VARIABLES
+                    .into_iter()
+                    .find(|v| v.name() == name)
+                    .ok_or_else(|| {
+                        format!("Line {}: unknown variable '{}'", line_number + 1, name)
+                    })?;
+                let settings = controls.linear_solvers.settings_mut(variable);
+                match setting {
+                    "method" => settings.method = SolverMethod::parse(parts[1])?,
+                    "preconditioner" => {
+                        settings.preconditioner = PreconditionerKind::parse(parts[1])?
+                    }
+                    "absolute_tolerance" => {
+                        settings.tolerance = Tolerance::Absolute(parts[1].parse()?)
+                    }
+                    "relative_tolerance" => {
+                        settings.tolerance = Tolerance::Relative(parts[1].parse()?)
+                    }
+                    "max_iters" => settings.max_iters = parts[1].parse()?,
+                    "restart" => settings.restart = parts[1].parse()?,
+                    "omega" => settings.omega = parts[1].parse()?,
+                    _ => {
+                        return Err(
+                            format!("Line {}: unknown key '{}'", line_number + 1, key).into()
+                        )
+                    }
+                }
+                continue;
+            }
+
+            let value: f64 = parts[1].parse()?;
+
+            if let Some(name) = key.strip_prefix("relaxation.") {
+                let variable = SolverControls::VARIABLES
+                    .into_iter()
+                    .find(|v| v.name() == name)
+                    .ok_or_else(|| {
+                        format!("Line {}: unknown variable '{}'", line_number + 1, name)
+                    })?;
+                controls.relaxation.set_factor(variable, value);
+            } else if let Some(name) = key.strip_prefix("pseudo_transient.") {
+                let pseudo = controls
+                    .pseudo_transient
+                    .get_or_insert_with(|| PseudoTransient::new(1.0));
+                match name {
+                    "cfl" => pseudo.cfl = value,
+                    "min_time_step" => pseudo.min_time_step = value,
+                    "max_time_step" => pseudo.max_time_step = value,
+                    _ => {
+                        return Err(
+                            format!("Line {}: unknown key '{}'", line_number + 1, key).into()
+                        )
+                    }
+                }
+            } else {
+                return Err(format!("Line {}: unknown key '{}'", line_number + 1, key).into());
+            }
+        }
+
+        controls.validate()?;
+        Ok(controls)
+    }
+}
+
+/// Implicit under-relaxation (Patankar): a_P / alpha * x_P = b + (1 - alpha) / alpha * a_P * x_P_old
+// Applied to every assembled equation once the steady solver exists; main only meshes so far
+#[allow(dead_code)]
+pub fn under_relax(
+    matrix: &mut SparseMatrix,
+    rhs: &mut [f64],
+    previous: &[f64],
+    alpha: f64,
+) -> Result<(), String> {
+    if rhs.len() != matrix.n_rows || previous.len() != matrix.n_rows {
+        return Err(format!(
+            "Wrong dimensions [A]={}x{}    [b]={}    [x_old]={}",
+            matrix.n_rows,
+            matrix.n_cols,
+            rhs.len(),
+            previous.len()
+        ));
+    }
+
+    if !(alpha > 0.0 && alpha <= 1.0) {
+        return Err(format!(
+            "Relaxation factor must be in (0, 1], got {}",
+            alpha
+        ));
+    }
+
+    if alpha == 1.0 {
+        return Ok(());
+    }
+
+    let mut has_diagonal = vec![false; matrix.n_rows];
+
+    for (row, col, value) in matrix.entries.iter_mut() {
+        if *row == *col {
+            let relaxed = *value / alpha;
+            rhs[*row] += (relaxed - *value) * previous[*row];
+            *value = relaxed;
+            has_diagonal[*row] = true;
+        }
+    }
+
+    match has_diagonal.iter().position(|d| !d) {
+        Some(row) => Err(format!("Row {} has no diagonal entry to relax", row)),
+        None => Ok(()),
+    }
+}
+
+/// Adds the pseudo-time term V / dt to the diagonal and V / dt * x_old to the right hand side,
+/// so the steady system is marched with a local time step per cell. Rows with duplicate
+/// diagonal entries get the term on the first one only.
+// Same as under_relax, waits for the steady solver
+#[allow(dead_code)]
+pub fn add_pseudo_time_term(
+    matrix: &mut SparseMatrix,
+    rhs: &mut [f64],
+    previous: &[f64],
+    volumes: &[f64],
+    time_steps: &[f64],
+) -> Result<(), String> {
+    let n = matrix.n_rows;
+    if rhs.len() != n || previous.len() != n || volumes.len() != n || time_steps.len() != n {
+        return Err(format!(
+            "Wrong dimensions [A]={}x{}    [b]={}    [x_old]={}    [V]={}    [dt]={}",
+            matrix.n_rows,
+            matrix.n_cols,
+            rhs.len(),
+            previous.len(),
+            volumes.len(),
+            time_steps.len()
+        ));
+    }
+
+    let mut has_diagonal = vec![false; n];
+
+    for (row, col, value) in matrix.entries.iter_mut() {
+        if *row == *col && !has_diagonal[*row] {
+            let inertia = volumes[*row] / time_steps[*row];
+            *value += inertia;
+            rhs[*row] += inertia * previous[*row];
+            has_diagonal[*row] = true;
+        }
+    }
+
+    for (row, _) in has_diagonal.iter().enumerate().filter(|(_, d)| !**d) {
+        let inertia = volumes[row] / time_steps[row];
+        matrix.entries.push((row, row, inertia));
+        rhs[row] += inertia * previous[row];
+    }
+
+    if has_diagonal.iter().any(|d| !d) {
+        matrix.preprocess();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_under_relax_keeps_solution() {
+        // A fixed point of the relaxed system is a solution of the original one
+        let rows = vec![0, 0, 1, 1];
+        let cols = vec![0, 1, 0, 1];
+        let values = vec![4.0, -1.0, -1.0, 4.0];
+        let mut a = SparseMatrix::from_vecs(&rows, &cols, &values);
+        let x = vec![1.0, 2.0];
+        let mut b = a.dot(&x).unwrap();
+
+        under_relax(&mut a, &mut b, &x, 0.5).unwrap();
+        let relaxed = a.dot(&x).unwrap();
+        assert_relative_eq!(relaxed[0], b[0], epsilon = 1e-12);
+        assert_relative_eq!(relaxed[1], b[1], epsilon = 1e-12);
+        assert_relative_eq!(a.entries[0].2, 8.0, epsilon = 1e-12);
+
+        assert!(under_relax(&mut a, &mut b, &x, 0.0).is_err());
+        assert!(under_relax(&mut a, &mut b, &x, 1.5).is_err());
+    }
+
+    #[test]
+    fn test_pseudo_time_term() {
+        // Row 1 has no diagonal entry, so the term creates one
+        let rows = vec![0, 0, 1];
+        let cols = vec![0, 1, 0];
+        let values = vec![4.0, -1.0, -1.0];
+        let mut a = SparseMatrix::from_vecs(&rows, &cols, &values);
+        let mut b = vec![1.0, 1.0];
+        let previous = [1.0, 2.0];
+
+        // V / dt is 4 on row 0 and 2 on row 1
+        add_pseudo_time_term(&mut a, &mut b, &previous, &[2.0, 3.0], &[0.5, 1.5]).unwrap();
+        assert_eq!(a.n_cols, 2);
+        assert_eq!(
+            a.entries,
+            vec![(0, 0, 8.0), (0, 1, -1.0), (1, 0, -1.0), (1, 1, 2.0)]
+        );
+        assert_eq!(b, vec![5.0, 5.0]);
+        assert_eq!(a.dot(&previous).unwrap(), vec![6.0, 3.0]);
+
+        assert!(add_pseudo_time_term(&mut a, &mut b, &previous, &[2.0], &[0.5, 1.5]).is_err());
+
+        // A diagonal split over two triplets gets the term once
+        let mut a = SparseMatrix::from_vecs(&vec![0, 0, 1], &vec![0, 0, 1], &vec![3.0, 1.0, 4.0]);
+        let mut b = vec![0.0, 0.0];
+        add_pseudo_time_term(&mut a, &mut b, &previous, &[2.0, 3.0], &[0.5, 1.5]).unwrap();
+        assert_eq!(a.dot(&previous).unwrap(), vec![8.0, 12.0]);
+        assert_eq!(b, vec![4.0, 4.0]);
+    }
+
+    #[test]
+    fn test_save_and_load_controls() {
+        let path = std::env::temp_dir().join("climate_flow_controls.txt");
+        let mut controls = SolverControls::new();
+        controls.relaxation.pressure = 0.2;
+        controls.pseudo_transient = Some(PseudoTransient::new(5.0));
+        controls.linear_solvers.velocity.method = SolverMethod::Gmres;
+        controls.linear_solvers.velocity.tolerance = Tolerance::Absolute(1e-8);
+        controls.save(&path).unwrap();
+
+        let loaded = SolverControls::load(&path).unwrap();
+        assert_relative_eq!(loaded.relaxation.pressure, 0.2);
+        assert_relative_eq!(loaded.relaxation.velocity, 0.7);
+        assert_relative_eq!(loaded.pseudo_transient.unwrap().cfl, 5.0);
+        let velocity = loaded.linear_solvers.settings(Variable::Velocity);
+        assert_eq!(velocity.method, SolverMethod::Gmres);
+        assert_eq!(velocity.tolerance, Tolerance::Absolute(1e-8));
+        assert_eq!(
+            loaded.linear_solvers.pressure.preconditioner,
+            PreconditionerKind::Ic0
+        );
+
+        for factor in ["0", "-0.5", "1.2"] {
+            std::fs::write(&path, format!("relaxation.velocity {}\n", factor)).unwrap();
+            assert!(SolverControls::load(&path).is_err());
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use mesh::mesher;
 
 mod boundary;
+mod controls;
 mod math;
 mod mesh;
 mod sparse_system;
@@ -12,27 +13,55 @@ fn main() {
     let tiff_path = testing_dir.join("elevation.tif");
     let stl_path = testing_dir.join("boundary.stl");
     let vtk_path = testing_dir.join("mesh.vtk");
+    let controls_path = testing_dir.join("controls.txt");
 
     let terrain = boundary::Grid::from_tiff(tiff_path).unwrap();
     let height_amp = terrain.z_max - terrain.z_min;
     let max_height = terrain.z_max + height_amp * 0.5;
-    let min_height = terrain.z_min - height_amp * 0.1;
-    let z_values = math::linspace(min_height, max_height, 5);
+    let grading = mesh::grading::VerticalGrading::new(2.0, 1.2, max_height)
+        .expect("Failed at vertical grading");
 
     let initial_conditions = mesher::InitialPhysics {
         z_ref: 500.0,
         speed_ref: 6.0,
         density_ref: 1.225,
         direction: 0.0,
+        align_with_wind: false,
         shear: 0.2,
         temperature: 300.0,
     };
 
+    let controls = if controls_path.exists() {
+        controls::SolverControls::load(controls_path).expect("Failed at loading controls")
+    } else {
+        let controls = controls::SolverControls::new();
+        controls
+            .save(controls_path)
+            .expect("Failed at saving default controls");
+        controls
+    };
+
     terrain
         .make_boundary(stl_path, height_amp * 0.5)
         .expect("Failed at saving boundary");
 
-    let mut mesh = mesh::mesher::Mesh::naive_mesh(&terrain, z_values);
+    // Turbines or masts to mesh finely around, the raster resolution is used without them
+    let sites: Vec<(f64, f64)> = Vec::new();
+    let lattice = if !sites.is_empty() {
+        let refinement = mesh::lattice::HorizontalRefinement::new(sites, 500.0, 20.0);
+        mesh::lattice::SurfaceLattice::refined(&terrain, &refinement)
+            .expect("Failed at refining terrain")
+    } else if initial_conditions.align_with_wind {
+        mesh::lattice::SurfaceLattice::aligned_with(&terrain, initial_conditions.direction)
+            .expect("Failed at resampling terrain")
+    } else {
+        mesh::lattice::SurfaceLattice::from_grid(&terrain)
+    };
+    let mut mesh = mesh::mesher::Mesh::graded_mesh(&lattice, &grading).expect("Failed at meshing");
+    mesh.renumber(&mesh.rcm_permutation())
+        .expect("Failed at renumbering mesh");
+    mesh.set_inflow_direction(initial_conditions.direction);
     mesh.define_initial_and_boundary_conditions(initial_conditions);
+    mesh.update_pseudo_time_steps(controls.pseudo_transient.as_ref());
     mesh.save_to_vtk(vtk_path).expect("Failed at saving vtk");
 }
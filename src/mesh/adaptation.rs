@@ -0,0 +1,365 @@
+use crate::mesh::{
+    geometry::{self, Vector},
+    mesher::{Mesh, Physics},
+    refinement::wall_points,
+};
+use rayon::prelude::*;
+
+const VON_KARMAN: f64 = 0.41;
+
+/// Per cell estimate of the discretisation error used to pick the cells to split
+// Chosen in AdaptationSettings, see adapt
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorIndicator {
+    /// Norm of the velocity gradient times the cell size, the velocity jump across the cell
+    VelocityGradient,
+    /// Shear production of turbulence of a mixing length model, integrated over the cell
+    TurbulenceProduction,
+}
+
+#[derive(Clone, Debug)]
+pub struct AdaptationSettings {
+    pub indicator: ErrorIndicator,
+    /// Share of the cells with the largest indicator split in every cycle
+    pub refine_fraction: f64,
+    pub max_level: usize,
+    pub max_cycles: usize,
+    /// No refinement that could take the mesh over this size
+    pub max_cells: usize,
+}
+
+/// Summary of one solve of the adaptation loop
+// Reported to the caller of adapt
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct AdaptationCycle {
+    pub n_cells: usize,
+    pub max_indicator: f64,
+    /// Cells split after this solve, the 2:1 balance included
+    pub refined: usize,
+}
+
+/// Gradient of velocity component i along direction j
+type VelocityGradient = [[f64; 3]; 3];
+
+fn components(vector: &Vector) -> [f64; 3] {
+    [vector.x, vector.y, vector.z]
+}
+
+fn frobenius(g: &VelocityGradient) -> f64 {
+    g.iter().flatten().map(|gij| gij * gij).sum::<f64>().sqrt()
+}
+
+// For reading the indicator from the controls file, which has no adaptation section yet
+#[allow(dead_code)]
+impl ErrorIndicator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorIndicator::VelocityGradient => "velocity_gradient",
+            ErrorIndicator::TurbulenceProduction => "turbulence_production",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<ErrorIndicator, String> {
+        [
+            ErrorIndicator::VelocityGradient,
+            ErrorIndicator::TurbulenceProduction,
+        ]
+        .into_iter()
+        .find(|indicator| indicator.name() == name)
+        .ok_or_else(|| format!("Unknown error indicator '{}'", name))
+    }
+}
+
+// Settings of adapt, which has no caller yet
+#[allow(dead_code)]
+impl AdaptationSettings {
+    /// Splits a tenth of the cells per cycle, three levels and five cycles at most
+    pub fn new(indicator: ErrorIndicator) -> AdaptationSettings {
+        AdaptationSettings {
+            indicator,
+            refine_fraction: 0.1,
+            max_level: 3,
+            max_cycles: 5,
+            max_cells: usize::MAX,
+        }
+    }
+}
+
+impl Mesh {
+    /// Green-Gauss gradient of a cell field. Face values average the two cells, boundary
+    /// faces take the value of the wall.
+    pub fn gradient(&self, field: impl Fn(&Physics) -> f64 + Sync) -> Vec<Vector> {
+        self.cells
+            .par_iter()
+            .map(|cell| {
+                let own = field(&cell.physics);
+                cell.walls
+                    .iter()
+                    .filter_map(|wall| wall_points(wall).map(|points| (wall, points)))
+                    .fold(Vector::new(0.0, 0.0, 0.0), |sum, (wall, points)| {
+                        let face_value = match wall.cells_id[1] {
+                            Some(other) => 0.5 * (own + field(&self.cells[other].physics)),
+                            None => field(&wall.physics),
+                        };
+                        // Wall area vectors point into the cell
+                        let area = geometry::quad_area_vector(&points);
+                        sum.add(&area.scale(-face_value))
+                    })
+                    .div(cell.volume)
+            })
+            .collect()
+    }
+
+    fn velocity_gradients(&self) -> Vec<VelocityGradient> {
+        let rows = [
+            self.gradient(|physics| physics.velocity.x),
+            self.gradient(|physics| physics.velocity.y),
+            self.gradient(|physics| physics.velocity.z),
+        ];
+        (0..self.cells.len())
+            .map(|id| rows.each_ref().map(|row| components(&row[id])))
+            .collect()
+    }
+
+    /// Frobenius norm of the velocity gradient of every cell
+    pub fn velocity_gradient_norms(&self) -> Vec<f64> {
+        self.velocity_gradients().iter().map(frobenius).collect()
+    }
+
+    pub fn error_indicators(&self, indicator: ErrorIndicator) -> Vec<f64> {
+        let gradients = self.velocity_gradients();
+        self.cells
+            .par_iter()
+            .zip(gradients.par_iter())
+            .map(|(cell, g)| {
+                let size = cell.volume.abs().cbrt();
+                match indicator {
+                    ErrorIndicator::VelocityGradient => frobenius(g) * size,
+                    ErrorIndicator::TurbulenceProduction => {
+                        // 2 S:S, with S the symmetric part of the gradient
+                        let strain_sq: f64 = (0..3)
+                            .flat_map(|i| (0..3).map(move |j| (i, j)))
+                            .map(|(i, j)| 0.5 * (g[i][j] + g[j][i]).powi(2))
+                            .sum();
+                        let mixing_length =
+                            VON_KARMAN * (cell.center.z - cell.ground_height).max(0.0);
+                        let eddy_viscosity = mixing_length * mixing_length * strain_sq.sqrt();
+                        eddy_viscosity * strain_sq * cell.volume.abs()
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Splits the marked cells and reconstructs the fields of the children linearly from the
+    /// parent value and gradient, so smooth solutions carry over without steps
+    pub fn refine_and_interpolate(&mut self, marked: &[bool]) -> Result<(), String> {
+        let scalars: [fn(&mut Physics) -> &mut f64; 7] = [
+            |p| &mut p.velocity.x,
+            |p| &mut p.velocity.y,
+            |p| &mut p.velocity.z,
+            |p| &mut p.pressure,
+            |p| &mut p.temperature,
+            |p| &mut p.density,
+            |p| &mut p.energy,
+        ];
+        let gradients: Vec<Vec<Vector>> = scalars
+            .iter()
+            .map(|access| {
+                self.gradient(|physics| {
+                    let mut copy = physics.clone();
+                    *access(&mut copy)
+                })
+            })
+            .collect();
+        let old: Vec<(Vector, usize)> = self
+            .cells
+            .iter()
+            .map(|cell| (cell.center, cell.level))
+            .collect();
+
+        let parents = self.refine(marked)?;
+
+        self.cells
+            .par_iter_mut()
+            .zip(parents.par_iter())
+            .filter(|(cell, parent)| cell.level > old[**parent].1)
+            .for_each(|(cell, parent)| {
+                let offset = cell.center.sub(&old[*parent].0);
+                for (access, gradient) in scalars.iter().zip(gradients.iter()) {
+                    *access(&mut cell.physics) += gradient[*parent].dot(&offset);
+                }
+            });
+
+        Ok(())
+    }
+
+    /// Solution-adaptive loop: solves with `solve`, splits the cells with the largest error
+    /// indicator and carries the solution over to the new mesh, until the cycles, levels
+    /// or cell budget run out. The mesh is left with the last solution on it.
+    // Needs a flow solve to drive it, Mesh::make_system is still a todo
+    #[allow(dead_code)]
+    pub fn adapt(
+        &mut self,
+        settings: &AdaptationSettings,
+        mut solve: impl FnMut(&mut Mesh) -> Result<(), String>,
+    ) -> Result<Vec<AdaptationCycle>, String> {
+        let mut cycles = Vec::new();
+
+        for cycle in 0..=settings.max_cycles {
+            solve(self)?;
+            let indicators = self.error_indicators(settings.indicator);
+            let max_indicator = indicators.iter().fold(0.0, |m: f64, v| m.max(*v));
+
+            let mut candidates: Vec<usize> = (0..self.cells.len())
+                .filter(|id| self.cells[*id].level < settings.max_level && indicators[*id] > 0.0)
+                .collect();
+            candidates.sort_by(|a, b| indicators[*b].total_cmp(&indicators[*a]));
+            let n_refine = ((settings.refine_fraction * self.cells.len() as f64).ceil() as usize)
+                .min(candidates.len());
+
+            // The budget counts the cells the 2:1 balance splits on top of the marked ones
+            let mut marked = vec![false; self.cells.len()];
+            for id in candidates.iter().take(n_refine) {
+                marked[*id] = true;
+            }
+            self.balance(&mut marked);
+            let n_split = marked.iter().filter(|m| **m).count();
+
+            let too_large = self.cells.len() + 7 * n_split > settings.max_cells;
+            let refined = if cycle == settings.max_cycles || too_large {
+                0
+            } else {
+                n_split
+            };
+            cycles.push(AdaptationCycle {
+                n_cells: self.cells.len(),
+                max_indicator,
+                refined,
+            });
+            if refined == 0 {
+                break;
+            }
+
+            self.refine_and_interpolate(&marked)?;
+        }
+
+        Ok(cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math;
+    use crate::mesh::{lattice::SurfaceLattice, mesher::WallKind};
+    use ndarray::Array2;
+
+    fn flat_mesh(n: usize, n_layers: usize) -> Mesh {
+        let nodes = Array2::from_shape_fn((n, n), |(i, j)| {
+            Vector::new(4.0 * i as f64, 4.0 * j as f64, 0.0)
+        });
+        let lattice = SurfaceLattice {
+            nodes,
+            ni: n,
+            nj: n,
+        };
+        let levels = math::linspace(0.0, 4.0 * n_layers as f64, n_layers + 1);
+        Mesh::from_lattice(&lattice, n_layers, |_node| levels.clone()).unwrap()
+    }
+
+    /// Sets a field everywhere, walls included, as a solver would
+    fn impose(mesh: &mut Mesh, velocity: impl Fn(&Vector) -> f64 + Sync) {
+        mesh.cells.par_iter_mut().for_each(|cell| {
+            cell.physics.velocity.x = velocity(&cell.center);
+            for wall in cell.walls.iter_mut() {
+                wall.physics.velocity.x = velocity(&wall.center);
+            }
+        });
+    }
+
+    #[test]
+    fn test_linear_field_interpolation() {
+        let mut mesh = flat_mesh(5, 4);
+        impose(&mut mesh, |x| 2.0 * x.z + x.y);
+        let gradient = mesh.gradient(|physics| physics.velocity.x);
+        for g in gradient.iter() {
+            assert!((g.x).abs() < 1e-9 && (g.y - 1.0).abs() < 1e-9 && (g.z - 2.0).abs() < 1e-9);
+        }
+
+        let marked = vec![true; mesh.cells.len()];
+        mesh.refine_and_interpolate(&marked).unwrap();
+        assert_eq!(mesh.cells.len(), 8 * 4 * 4 * 4);
+        for cell in mesh.cells.iter() {
+            let expected = 2.0 * cell.center.z + cell.center.y;
+            assert!((cell.physics.velocity.x - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_adaptation_follows_shear_layer() {
+        let mut mesh = flat_mesh(6, 6);
+        let shear_layer = |x: &Vector| (x.z - 12.0).tanh();
+        let mut solves = 0;
+
+        let mut settings = AdaptationSettings::new(ErrorIndicator::VelocityGradient);
+        settings.max_level = 2;
+        settings.max_cycles = 3;
+        let cycles = mesh
+            .adapt(&settings, |mesh| {
+                solves += 1;
+                impose(mesh, shear_layer);
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(solves, cycles.len());
+        assert_eq!(cycles.len(), 4);
+        for pair in cycles.windows(2) {
+            assert!(pair[0].refined > 0);
+            assert_eq!(pair[1].n_cells, pair[0].n_cells + 7 * pair[0].refined);
+        }
+        assert_eq!(cycles[3].refined, 0);
+        // The 2:1 balance splits a cell more than the marked share of the second mesh
+        assert_eq!(cycles[1].refined, 27);
+        // Resolving the layer lowers the largest velocity jump across a cell
+        assert!(cycles[3].max_indicator < 0.9 * cycles[0].max_indicator);
+        let level_at = |z: f64| {
+            mesh.cells
+                .iter()
+                .filter(|cell| (cell.center.z - z).abs() < 1.0)
+                .map(|cell| cell.level)
+                .max()
+                .unwrap()
+        };
+        assert_eq!(level_at(12.5), 2);
+        assert_eq!(level_at(22.0), 0);
+        assert!(mesh
+            .cells
+            .iter()
+            .flat_map(|cell| cell.walls.iter())
+            .any(|wall| matches!(wall.kind, WallKind::Terrain)));
+
+        // 26 marked cells would fit, the balanced 27 do not
+        let mut budget = flat_mesh(6, 6);
+        settings.max_cells = 440;
+        let cycles = budget
+            .adapt(&settings, |mesh| {
+                impose(mesh, shear_layer);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(cycles.len(), 2);
+        assert_eq!(cycles[1].refined, 0);
+        assert_eq!(budget.cells.len(), 255);
+
+        let production = mesh.error_indicators(ErrorIndicator::TurbulenceProduction);
+        assert!(production.iter().all(|p| *p >= 0.0));
+        assert_eq!(
+            ErrorIndicator::parse("turbulence_production"),
+            Ok(ErrorIndicator::TurbulenceProduction)
+        );
+    }
+}
@@ -46,6 +46,14 @@ impl Vector {
         }
     }
 
+    pub fn scale(&self, factor: f64) -> Vector {
+        Vector {
+            x: self.x * factor,
+            y: self.y * factor,
+            z: self.z * factor,
+        }
+    }
+
     pub fn dot(&self, other: &Vector) -> f64 {
         self.x * other.x + self.y * other.y + self.z * other.z
     }
@@ -111,3 +119,32 @@ pub fn average_points(points: &[Vector]) -> Vector {
         .fold(Vector::new(0.0, 0.0, 0.0), |acc, point| acc.add(point))
         .div(points.len() as f64)
 }
+
+/// Area vector of a quad, normal to its mean plane. Exact for warped quads too.
+pub fn quad_area_vector(points: &[Vector; 4]) -> Vector {
+    points[2]
+        .sub(&points[0])
+        .cross(&points[3].sub(&points[1]))
+        .div(2.0)
+}
+
+/// Volume of a hexahedron with the vertex numbering of `Cell`, faces allowed to be warped.
+/// Sum over the faces of centre . area vector, by the divergence theorem.
+pub fn hexahedron_volume(vertices: &[Vector]) -> f64 {
+    let v = vertices;
+    // Counter-clockwise seen from outside
+    let faces = [
+        [v[0], v[4], v[5], v[1]],
+        [v[3], v[2], v[6], v[7]],
+        [v[0], v[1], v[2], v[3]],
+        [v[4], v[7], v[6], v[5]],
+        [v[0], v[3], v[7], v[4]],
+        [v[1], v[5], v[6], v[2]],
+    ];
+
+    faces
+        .iter()
+        .map(|face| average_points(face).dot(&quad_area_vector(face)))
+        .sum::<f64>()
+        / 3.0
+}
@@ -0,0 +1,171 @@
+/// Geometric distribution of the cell heights in every column: the first cell above the
+/// ground is `first_cell_height` tall and each cell is `expansion_ratio` times taller than
+/// the one below, up to `domain_top`.
+#[derive(Clone, Debug)]
+pub struct VerticalGrading {
+    pub first_cell_height: f64,
+    pub expansion_ratio: f64,
+    pub domain_top: f64,
+}
+
+impl VerticalGrading {
+    pub fn new(
+        first_cell_height: f64,
+        expansion_ratio: f64,
+        domain_top: f64,
+    ) -> Result<VerticalGrading, String> {
+        if !(first_cell_height > 0.0 && first_cell_height.is_finite()) {
+            return Err(format!(
+                "First cell height must be positive, got {}",
+                first_cell_height
+            ));
+        }
+        if !(expansion_ratio >= 1.0 && expansion_ratio.is_finite()) {
+            return Err(format!(
+                "Expansion ratio must be at least 1, got {}",
+                expansion_ratio
+            ));
+        }
+        if !domain_top.is_finite() {
+            return Err(format!("Domain top must be finite, got {}", domain_top));
+        }
+
+        Ok(VerticalGrading {
+            first_cell_height,
+            expansion_ratio,
+            domain_top,
+        })
+    }
+
+    /// Height of `n` cells growing by `ratio` from the first cell height
+    fn stack_height(&self, ratio: f64, n: usize) -> f64 {
+        (0..n)
+            .map(|m| self.first_cell_height * ratio.powi(m as i32))
+            .sum()
+    }
+
+    /// Cells needed to reach the top from `ground` with the nominal expansion ratio. Using
+    /// the lowest ground of the domain gives every column the same number of cells.
+    pub fn n_layers(&self, ground: f64) -> Result<usize, String> {
+        let depth = self.domain_top - ground;
+        if depth <= self.first_cell_height {
+            return Err(format!(
+                "Domain top {} leaves no room for a first cell of {} above {}",
+                self.domain_top, self.first_cell_height, ground
+            ));
+        }
+
+        Ok((1..)
+            .find(|n| self.stack_height(self.expansion_ratio, *n) >= depth)
+            .unwrap())
+    }
+
+    /// The `n_layers + 1` vertex heights of a column from `ground` to the top. The first cell
+    /// keeps its height and the expansion ratio of the column is adjusted so the cells end
+    /// exactly at the top: it is below the nominal one for columns shallower than the one
+    /// `n_layers` was computed for. None when the column is too shallow for `n_layers` cells
+    /// of at least the first cell height, as the ratio would fall below 1.
+    pub fn levels(&self, ground: f64, n_layers: usize) -> Option<Vec<f64>> {
+        let depth = self.domain_top - ground;
+        if n_layers == 0 || self.stack_height(1.0, n_layers) > depth {
+            return None;
+        }
+
+        // The stack height grows with the ratio, so bisection finds the one matching the depth
+        let (mut low, mut high) = (1.0, self.expansion_ratio);
+        while self.stack_height(high, n_layers) < depth {
+            high *= 2.0;
+        }
+        for _ in 0..200 {
+            let ratio = 0.5 * (low + high);
+            if self.stack_height(ratio, n_layers) < depth {
+                low = ratio;
+            } else {
+                high = ratio;
+            }
+        }
+
+        let ratio = 0.5 * (low + high);
+        let mut levels = Vec::with_capacity(n_layers + 1);
+        let mut z = ground;
+        levels.push(z);
+        for m in 0..n_layers - 1 {
+            z += self.first_cell_height * ratio.powi(m as i32);
+            levels.push(z);
+        }
+        levels.push(self.domain_top);
+        Some(levels)
+    }
+
+    /// Relative heights, from 0 at the ground to 1 at the top, of the vertices of the column
+    /// standing on `lowest_ground`. Shallow columns that cannot keep the first cell height
+    /// follow this distribution, so their cells still grow upwards. When even the deepest
+    /// column is too shallow for that its cells are uniform.
+    pub fn sigmas(&self, lowest_ground: f64) -> Result<Vec<f64>, String> {
+        let n_layers = self.n_layers(lowest_ground)?;
+        let depth = self.domain_top - lowest_ground;
+        Ok(match self.levels(lowest_ground, n_layers) {
+            Some(levels) => levels.iter().map(|z| (z - lowest_ground) / depth).collect(),
+            None => (0..=n_layers).map(|k| k as f64 / n_layers as f64).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vertical_grading() {
+        let grading = VerticalGrading::new(2.0, 1.2, 500.0).unwrap();
+        let n = grading.n_layers(100.0).unwrap();
+        assert!(grading.stack_height(1.2, n - 1) < 400.0);
+        let sigmas = grading.sigmas(100.0).unwrap();
+        assert_eq!(sigmas.len(), n + 1);
+
+        let deepest = grading.levels(100.0, n).unwrap();
+        let deepest_ratio = (deepest[2] - deepest[1]) / (deepest[1] - deepest[0]);
+        for ground in [100.0, 250.0, 480.0] {
+            // The column at 480 m is too shallow for n cells of 2 m, it follows the sigmas
+            let levels = grading.levels(ground, n).unwrap_or_else(|| {
+                assert_eq!(ground, 480.0);
+                sigmas
+                    .iter()
+                    .map(|s| ground + s * (500.0 - ground))
+                    .collect()
+            });
+            assert_eq!(levels.len(), n + 1);
+            assert_eq!(levels[0], ground);
+            assert!((levels[n] - 500.0).abs() < 1e-9);
+
+            let heights: Vec<f64> = levels.windows(2).map(|pair| pair[1] - pair[0]).collect();
+            assert!(heights.iter().all(|h| *h > 0.0));
+            let ratio = heights[1] / heights[0];
+            for pair in heights.windows(2) {
+                assert!((pair[1] / pair[0] - ratio).abs() < 1e-6);
+            }
+            assert!(ratio >= 1.0);
+            assert!(ratio <= 1.2 + 1e-12);
+            if ground == 480.0 {
+                assert!((ratio - deepest_ratio).abs() < 1e-6);
+                assert!(heights[0] < 2.0);
+            } else {
+                assert!((heights[0] - 2.0).abs() < 1e-9);
+            }
+        }
+
+        assert!(grading.levels(100.0, 0).is_none());
+        assert!(grading.n_layers(499.0).is_err());
+        assert!(VerticalGrading::new(2.0, 0.9, 500.0).is_err());
+        assert!(VerticalGrading::new(2.0, f64::NAN, 500.0).is_err());
+        assert!(VerticalGrading::new(f64::NAN, 1.2, 500.0).is_err());
+        assert!(VerticalGrading::new(2.0, 1.2, f64::NAN).is_err());
+
+        // Without growth the deepest column cannot keep 2 m cells over 5 m, they are uniform
+        let uniform = VerticalGrading::new(2.0, 1.0, 5.0).unwrap();
+        assert_eq!(
+            uniform.sigmas(0.0).unwrap(),
+            vec![0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::{
+    boundary::Grid,
+    mesh::geometry::Vector,
+    mesh::mesher::Mesh,
+    sparse_system::multigrid::{AmgParameters, Multigrid},
+    sparse_system::sparse_matrix::SparseMatrix,
+};
+use std::collections::HashMap;
+
+/// Nested staircase meshes obtained by coarsening the terrain raster and the vertical
+/// levels by a factor of two. Level 0 is the finest mesh.
+pub struct MeshHierarchy {
+    pub meshes: Vec<Mesh>,
+    /// For every level but the coarsest, the cell of the next coarser mesh containing each cell
+    pub coarse_cells: Vec<Vec<usize>>,
+}
+
+fn coarsen_levels(zs: &[f64]) -> Vec<f64> {
+    let mut coarse: Vec<f64> = zs.iter().step_by(2).copied().collect();
+    if !(zs.len() - 1).is_multiple_of(2) {
+        coarse.push(zs[zs.len() - 1]);
+    }
+    coarse
+}
+
+fn column_of(grid: &Grid, x: f64, y: f64) -> (usize, usize) {
+    let i = ((x - grid.x_min) / grid.x_res).floor().max(0.0) as usize;
+    let j = ((y - grid.y_min) / grid.y_res).floor().max(0.0) as usize;
+    (
+        i.min(grid.nx.saturating_sub(2)),
+        j.min(grid.ny.saturating_sub(2)),
+    )
+}
+
+fn nearest_cell(mesh: &Mesh, ids: impl Iterator<Item = usize>, point: &Vector) -> usize {
+    ids.map(|id| (id, mesh.cells[id].center.sub(point).mag()))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(id, _distance)| id)
+        .unwrap()
+}
+
+/// Cell of `coarse` whose column contains the centre of each cell of `fine`, the closest in
+/// height when the column has several candidates
+fn locate(fine: &Mesh, coarse: &Mesh, coarse_grid: &Grid) -> Vec<usize> {
+    let mut columns: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    for cell in coarse.cells.iter() {
+        columns
+            .entry(column_of(coarse_grid, cell.center.x, cell.center.y))
+            .or_default()
+            .push(cell.id);
+    }
+
+    fine.cells
+        .iter()
+        .map(|cell| {
+            let column = column_of(coarse_grid, cell.center.x, cell.center.y);
+            match columns.get(&column) {
+                Some(ids) => nearest_cell(coarse, ids.iter().copied(), &cell.center),
+                None => nearest_cell(coarse, 0..coarse.cells.len(), &cell.center),
+            }
+        })
+        .collect()
+}
+
+// Built on naive_mesh for the pressure solves and full multigrid initialisation of the flow
+// solver, main meshes with graded_mesh and solves nothing yet
+#[allow(dead_code)]
+impl MeshHierarchy {
+    pub fn naive(terrain: &Grid, zs: Vec<f64>, n_levels: usize) -> MeshHierarchy {
+        let mut meshes = vec![Mesh::naive_mesh(terrain, zs.clone())];
+        let mut coarse_cells = Vec::new();
+        let mut zs = zs;
+
+        while meshes.len() < n_levels && zs.len() >= 3 {
+            // Every node of the level coarsened twice is a node of the raster coarsened by four
+            let grid = terrain.coarsen(1 << meshes.len());
+            if grid.nx < 3 || grid.ny < 3 {
+                break;
+            }
+            zs = coarsen_levels(&zs);
+
+            let coarse = Mesh::naive_mesh(&grid, zs.clone());
+            coarse_cells.push(locate(meshes.last().unwrap(), &coarse, &grid));
+            meshes.push(coarse);
+        }
+
+        MeshHierarchy {
+            meshes,
+            coarse_cells,
+        }
+    }
+
+    pub fn n_levels(&self) -> usize {
+        self.meshes.len()
+    }
+
+    /// Piecewise constant prolongations for the finest mesh unknowns, finest first. Coarse
+    /// cells that contain no finer cell are dropped so every coarse operator stays regular.
+    pub fn prolongations(&self) -> Vec<SparseMatrix> {
+        let mut active: Vec<usize> = (0..self.meshes[0].cells.len()).collect();
+        let mut prolongations = Vec::with_capacity(self.coarse_cells.len());
+
+        for map in self.coarse_cells.iter() {
+            let mut local: HashMap<usize, usize> = HashMap::new();
+            let mut next_active = Vec::new();
+            let mut rows = Vec::with_capacity(active.len());
+            let mut cols = Vec::with_capacity(active.len());
+
+            for (row, fine_id) in active.iter().enumerate() {
+                let coarse_id = map[*fine_id];
+                let col = *local.entry(coarse_id).or_insert_with(|| {
+                    next_active.push(coarse_id);
+                    next_active.len() - 1
+                });
+                rows.push(row);
+                cols.push(col);
+            }
+
+            let values = vec![1.0; rows.len()];
+            prolongations.push(SparseMatrix::from_vecs(&rows, &cols, &values));
+            active = next_active;
+        }
+
+        prolongations
+    }
+
+    /// Geometric multigrid for a system assembled on the finest mesh
+    pub fn multigrid(
+        &self,
+        matrix: &SparseMatrix,
+        params: &AmgParameters,
+    ) -> Result<Multigrid, String> {
+        if matrix.n_rows != self.meshes[0].cells.len() {
+            return Err(format!(
+                "Matrix with {} rows does not match a mesh with {} cells",
+                matrix.n_rows,
+                self.meshes[0].cells.len()
+            ));
+        }
+        Multigrid::from_prolongations(matrix, self.prolongations(), params)
+    }
+
+    /// Injects a cell field of mesh `level + 1` onto mesh `level`
+    pub fn prolong_field(&self, level: usize, coarse: &[f64]) -> Vec<f64> {
+        self.coarse_cells[level]
+            .iter()
+            .map(|coarse_id| coarse[*coarse_id])
+            .collect()
+    }
+
+    /// Volume weighted average of a cell field of mesh `level` onto mesh `level + 1`
+    pub fn restrict_field(&self, level: usize, fine: &[f64]) -> Vec<f64> {
+        let n_coarse = self.meshes[level + 1].cells.len();
+        let mut sums = vec![0.0; n_coarse];
+        let mut volumes = vec![0.0; n_coarse];
+
+        for (cell, coarse_id) in self.meshes[level]
+            .cells
+            .iter()
+            .zip(self.coarse_cells[level].iter())
+        {
+            sums[*coarse_id] += fine[cell.id] * cell.volume.abs();
+            volumes[*coarse_id] += cell.volume.abs();
+        }
+
+        sums.iter()
+            .zip(volumes.iter())
+            .map(|(sum, volume)| if *volume > 0.0 { sum / volume } else { 0.0 })
+            .collect()
+    }
+
+    /// Copies the cell physics of mesh `level + 1` onto mesh `level`
+    pub fn prolong_physics(&mut self, level: usize) {
+        let (fine, coarse) = self.meshes.split_at_mut(level + 1);
+        let fine = &mut fine[level];
+        let coarse = &coarse[0];
+
+        for (cell, coarse_id) in fine.cells.iter_mut().zip(self.coarse_cells[level].iter()) {
+            cell.physics = coarse.cells[*coarse_id].physics.clone();
+        }
+    }
+
+    /// Full multigrid initialisation: solves on the coarsest mesh and uses every solution as
+    /// the initial field of the next finer mesh
+    pub fn full_multigrid_initialisation(&mut self, mut solve: impl FnMut(&mut Mesh)) {
+        for level in (0..self.meshes.len()).rev() {
+            if level + 1 < self.meshes.len() {
+                self.prolong_physics(level);
+            }
+            solve(&mut self.meshes[level]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_system::sparse_system::SparseSystem;
+    use ndarray::Array2;
+
+    fn sloped_grid(n: usize, base: f64, slope: f64) -> Grid {
+        let elevations =
+            Array2::from_shape_fn((n, n), |(i, j)| base + slope * (i as f64 + 0.5 * j as f64));
+        Grid {
+            elevations,
+            x_min: 0.0,
+            y_min: 0.0,
+            x_max: (n - 1) as f64 * 10.0,
+            y_max: (n - 1) as f64 * 10.0,
+            x_res: 10.0,
+            y_res: 10.0,
+            z_min: base,
+            z_max: base + 1.5 * slope * (n - 1) as f64,
+            nx: n,
+            ny: n,
+        }
+    }
+
+    /// Diffusion between the cells sharing a wall, fixed value on the boundary walls
+    fn diffusion_matrix(mesh: &Mesh) -> SparseMatrix {
+        let (mut rows, mut cols, mut values) = (Vec::new(), Vec::new(), Vec::new());
+        for cell in mesh.cells.iter() {
+            for wall in cell.walls.iter() {
+                rows.push(cell.id);
+                cols.push(cell.id);
+                values.push(1.0);
+                if let Some(other) = wall.cells_id[1] {
+                    rows.push(cell.id);
+                    cols.push(other);
+                    values.push(-1.0);
+                }
+            }
+        }
+        let mut matrix = SparseMatrix::from_vecs(&rows, &cols, &values);
+        matrix.sum_duplicates();
+        matrix
+    }
+
+    #[test]
+    fn test_hierarchy_levels() {
+        // The terrain stays below the lowest level, every column has all the layers
+        let grid = sloped_grid(17, 1.0, 0.3);
+        let zs = crate::math::linspace(0.0, 100.0, 9);
+        let hierarchy = MeshHierarchy::naive(&grid, zs, 4);
+
+        let n_cells: Vec<usize> = hierarchy.meshes.iter().map(|m| m.cells.len()).collect();
+        assert_eq!(n_cells, vec![16 * 16 * 8, 8 * 8 * 4, 4 * 4 * 2, 2 * 2]);
+        // Each coarse cell is split in two along the three axes
+        for (level, map) in hierarchy.coarse_cells.iter().enumerate() {
+            let mut children = vec![0; n_cells[level + 1]];
+            for coarse_id in map.iter() {
+                children[*coarse_id] += 1;
+            }
+            assert!(children.iter().all(|count| *count == 8), "level {}", level);
+        }
+
+        for level in 0..hierarchy.coarse_cells.len() {
+            let ones = vec![1.0; n_cells[level + 1]];
+            assert!(hierarchy
+                .prolong_field(level, &ones)
+                .iter()
+                .all(|v| *v == 1.0));
+            let restricted = hierarchy.restrict_field(level, &vec![1.0; n_cells[level]]);
+            assert!(restricted.iter().all(|v| (v - 1.0).abs() < 1e-12));
+        }
+        for prolongation in hierarchy.prolongations().iter() {
+            let prolonged = prolongation.dot(&vec![1.0; prolongation.n_cols]).unwrap();
+            assert!(prolonged.iter().all(|v| *v == 1.0));
+        }
+    }
+
+    #[test]
+    fn test_hierarchy_multigrid() {
+        // Staircase terrain, columns have different numbers of layers
+        let grid = sloped_grid(17, 10.0, 1.0);
+        let zs = crate::math::linspace(0.0, 100.0, 9);
+        let hierarchy = MeshHierarchy::naive(&grid, zs, 3);
+        assert_eq!(hierarchy.n_levels(), 3);
+
+        let prolongations = hierarchy.prolongations();
+        assert_eq!(prolongations.len(), 2);
+        assert_eq!(prolongations[0].n_rows, hierarchy.meshes[0].cells.len());
+        assert_eq!(prolongations[1].n_rows, prolongations[0].n_cols);
+        assert!(prolongations[1].n_cols <= hierarchy.meshes[2].cells.len());
+        let prolonged = hierarchy.prolong_field(0, &vec![1.0; hierarchy.meshes[1].cells.len()]);
+        assert!(prolonged.iter().all(|v| *v == 1.0));
+
+        let a = diffusion_matrix(&hierarchy.meshes[0]);
+        let n = a.n_rows;
+        let b = vec![1.0; n];
+        let multigrid = hierarchy.multigrid(&a, &AmgParameters::new()).unwrap();
+        assert_eq!(multigrid.levels.len(), 3);
+
+        let system = SparseSystem::new(&a, &b);
+        let result = system.multigrid_solve(&vec![0.0; n], &multigrid, 1e-16, 50);
+        assert!(result.converged, "{}", result.message);
+    }
+}
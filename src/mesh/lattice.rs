@@ -0,0 +1,370 @@
+use crate::{boundary::Grid, math, mesh::geometry::Vector};
+use ndarray::Array2;
+
+/// Structured layout of the mesh columns seen from above. Node (i, j) holds a horizontal
+/// position and the ground elevation there, and the quad of nodes (i, j), (i + 1, j),
+/// (i, j + 1), (i + 1, j + 1) is the footprint of one column of cells.
+#[derive(Clone)]
+pub struct SurfaceLattice {
+    pub nodes: Array2<Vector>,
+    pub ni: usize,
+    pub nj: usize,
+}
+
+/// Horizontal resolution of a lattice: cells of `fine_size` over the box containing every
+/// site with a margin of `radius`, growing by `expansion_ratio` from it towards the domain
+/// boundaries, up to `max_size`.
+#[derive(Clone, Debug)]
+pub struct HorizontalRefinement {
+    /// (x, y) of the turbines, masts or other points of interest
+    pub sites: Vec<(f64, f64)>,
+    pub radius: f64,
+    pub fine_size: f64,
+    pub expansion_ratio: f64,
+    pub max_size: f64,
+}
+
+impl HorizontalRefinement {
+    /// Grows by 1.2 without a maximum cell size
+    pub fn new(sites: Vec<(f64, f64)>, radius: f64, fine_size: f64) -> HorizontalRefinement {
+        HorizontalRefinement {
+            sites,
+            radius,
+            fine_size,
+            expansion_ratio: 1.2,
+            max_size: f64::INFINITY,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.sites.is_empty() {
+            return Err("Horizontal refinement needs at least one site".to_string());
+        }
+        if self.fine_size <= 0.0 || self.radius < 0.0 || self.max_size < self.fine_size {
+            return Err(format!(
+                "Invalid horizontal refinement: fine size {}, radius {}, max size {}",
+                self.fine_size, self.radius, self.max_size
+            ));
+        }
+        if self.expansion_ratio < 1.0 {
+            return Err(format!(
+                "Expansion ratio must be at least 1, got {}",
+                self.expansion_ratio
+            ));
+        }
+        Ok(())
+    }
+
+    /// Node coordinates after `from` up to `to` included, with sizes growing from the fine one
+    fn grow(&self, from: f64, to: f64) -> Vec<f64> {
+        let mut points = Vec::new();
+        if to <= from {
+            return points;
+        }
+
+        let mut x = from;
+        let mut size = self.fine_size;
+        loop {
+            size = (size * self.expansion_ratio).min(self.max_size);
+            if x + size >= to {
+                break;
+            }
+            x += size;
+            points.push(x);
+        }
+
+        // A sliver before the boundary is merged into the previous cell
+        if points.last().is_some_and(|last| to - last < 0.5 * size) {
+            points.pop();
+        }
+        points.push(to);
+        points
+    }
+
+    /// Node coordinates from `start` to `end` along one axis, fine over `zone_start..zone_end`
+    fn coordinates(&self, start: f64, end: f64, zone_start: f64, zone_end: f64) -> Vec<f64> {
+        let zone_start = zone_start.clamp(start, end);
+        let zone_end = zone_end.clamp(start, end);
+        let n = ((zone_end - zone_start) / self.fine_size).ceil() as usize;
+        let zone =
+            (0..=n).map(|m| zone_start + (zone_end - zone_start) * m as f64 / n.max(1) as f64);
+
+        let before = self.grow(-zone_start, -start);
+        let after = self.grow(zone_end, end);
+        before
+            .iter()
+            .rev()
+            .map(|x| -x)
+            .chain(zone.take(n + 1))
+            .chain(after)
+            .collect()
+    }
+}
+
+impl SurfaceLattice {
+    /// One node per raster node of the terrain
+    pub fn from_grid(terrain: &Grid) -> SurfaceLattice {
+        SurfaceLattice {
+            nodes: Array2::from_shape_fn((terrain.nx, terrain.ny), |(i, j)| terrain.xyz(i, j)),
+            ni: terrain.nx,
+            nj: terrain.ny,
+        }
+    }
+
+    /// Lattice refined around the sites of `refinement`, whatever the raster resolution.
+    /// The ground elevations are interpolated from the terrain.
+    pub fn refined(
+        terrain: &Grid,
+        refinement: &HorizontalRefinement,
+    ) -> Result<SurfaceLattice, String> {
+        refinement.validate()?;
+
+        let (x_start, x_end) = (terrain.x(0), terrain.x(terrain.nx - 1));
+        let (y_start, y_end) = (terrain.y(0), terrain.y(terrain.ny - 1));
+        let (low, high) = (x_start.min(x_end), x_start.max(x_end));
+        let (south, north) = (y_start.min(y_end), y_start.max(y_end));
+
+        let site_x = refinement.sites.iter().map(|site| site.0);
+        let site_y = refinement.sites.iter().map(|site| site.1);
+        let xs = refinement.coordinates(
+            low,
+            high,
+            site_x.clone().fold(f64::MAX, f64::min) - refinement.radius,
+            site_x.fold(f64::MIN, f64::max) + refinement.radius,
+        );
+        let ys = refinement.coordinates(
+            south,
+            north,
+            site_y.clone().fold(f64::MAX, f64::min) - refinement.radius,
+            site_y.fold(f64::MIN, f64::max) + refinement.radius,
+        );
+
+        if xs.len() < 2 || ys.len() < 2 {
+            return Err("Refinement zone leaves no cell in the domain".to_string());
+        }
+
+        Ok(SurfaceLattice {
+            nodes: Array2::from_shape_fn((xs.len(), ys.len()), |(i, j)| {
+                Vector::new(xs[i], ys[j], terrain.elevation_at(xs[i], ys[j]))
+            }),
+            ni: xs.len(),
+            nj: ys.len(),
+        })
+    }
+
+    /// Lattice of `n` by `n` nodes covering the disk of `radius` around `centre`. The square
+    /// of nodes is mapped onto the disk so the lattice boundary lies on the circle, leaving no
+    /// corners for diagonal inflow; the four corner columns have one flat angle.
+    pub fn disk(
+        terrain: &Grid,
+        centre: (f64, f64),
+        radius: f64,
+        n: usize,
+    ) -> Result<SurfaceLattice, String> {
+        if n < 3 || radius <= 0.0 {
+            return Err(format!(
+                "Cannot build a disk of radius {} with {} nodes per side",
+                radius, n
+            ));
+        }
+
+        let (x_start, x_end) = (terrain.x(0), terrain.x(terrain.nx - 1));
+        let (y_start, y_end) = (terrain.y(0), terrain.y(terrain.ny - 1));
+        let inside = |value: f64, start: f64, end: f64| {
+            value - radius >= start.min(end) && value + radius <= start.max(end)
+        };
+        if !inside(centre.0, x_start, x_end) || !inside(centre.1, y_start, y_end) {
+            return Err(format!(
+                "Disk of radius {} around ({}, {}) goes beyond the terrain",
+                radius, centre.0, centre.1
+            ));
+        }
+
+        let unit = |m: usize| 2.0 * m as f64 / (n - 1) as f64 - 1.0;
+        Ok(SurfaceLattice {
+            nodes: Array2::from_shape_fn((n, n), |(i, j)| {
+                let (u, v) = (unit(i), unit(j));
+                let x = centre.0 + radius * u * (1.0 - 0.5 * v * v).sqrt();
+                let y = centre.1 + radius * v * (1.0 - 0.5 * u * u).sqrt();
+                Vector::new(x, y, terrain.elevation_at(x, y))
+            }),
+            ni: n,
+            nj: n,
+        })
+    }
+
+    /// Lattice whose i axis points along `direction`, in degrees as `InitialPhysics::direction`,
+    /// so the first and last columns face the wind and the other sides are parallel to it.
+    /// It covers the largest square of that orientation centred on the terrain, at the raster
+    /// resolution, with the elevations resampled from the raster.
+    pub fn aligned_with(terrain: &Grid, direction: f64) -> Result<SurfaceLattice, String> {
+        if terrain.nx < 2 || terrain.ny < 2 {
+            return Err(format!(
+                "Cannot resample a {}x{} terrain",
+                terrain.nx, terrain.ny
+            ));
+        }
+
+        let (cos, sin) = (
+            math::as_rads(direction).cos(),
+            math::as_rads(direction).sin(),
+        );
+        let width = (terrain.x(terrain.nx - 1) - terrain.x(0)).abs();
+        let depth = (terrain.y(terrain.ny - 1) - terrain.y(0)).abs();
+        let centre = (
+            0.5 * (terrain.x(0) + terrain.x(terrain.nx - 1)),
+            0.5 * (terrain.y(0) + terrain.y(terrain.ny - 1)),
+        );
+
+        // The corners of the rotated square stay within the terrain extent
+        let half = 0.5 * width.min(depth) / (cos.abs() + sin.abs());
+        let resolution = terrain.x_res.abs().min(terrain.y_res.abs());
+        let n = ((2.0 * half / resolution).ceil() as usize).max(1) + 1;
+        let step = 2.0 * half / (n - 1) as f64;
+
+        Ok(SurfaceLattice {
+            nodes: Array2::from_shape_fn((n, n), |(i, j)| {
+                let along = -half + step * i as f64;
+                let across = -half + step * j as f64;
+                let x = centre.0 + along * cos - across * sin;
+                let y = centre.1 + along * sin + across * cos;
+                Vector::new(x, y, terrain.elevation_at(x, y))
+            }),
+            ni: n,
+            nj: n,
+        })
+    }
+
+    pub fn node(&self, i: usize, j: usize) -> &Vector {
+        &self.nodes[[i, j]]
+    }
+
+    pub fn n_columns(&self) -> usize {
+        (self.ni - 1) * (self.nj - 1)
+    }
+
+    pub fn min_ground(&self) -> f64 {
+        self.nodes
+            .iter()
+            .map(|node| node.z)
+            .fold(f64::MAX, f64::min)
+    }
+
+    pub fn max_ground(&self) -> f64 {
+        self.nodes
+            .iter()
+            .map(|node| node.z)
+            .fold(f64::MIN, f64::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sloped_grid(n: usize, res: f64) -> Grid {
+        let elevations = Array2::from_shape_fn((n, n), |(i, j)| 2.0 * i as f64 + j as f64);
+        Grid {
+            elevations,
+            x_min: 0.0,
+            y_min: 0.0,
+            x_max: (n - 1) as f64 * res,
+            y_max: (n - 1) as f64 * res,
+            x_res: res,
+            y_res: res,
+            z_min: 0.0,
+            z_max: 3.0 * (n - 1) as f64,
+            nx: n,
+            ny: n,
+        }
+    }
+
+    #[test]
+    fn test_refinement_around_sites() {
+        let terrain = sloped_grid(201, 10.0);
+        let mut refinement =
+            HorizontalRefinement::new(vec![(900.0, 1000.0), (1100.0, 1000.0)], 100.0, 4.0);
+        refinement.max_size = 150.0;
+        let lattice = SurfaceLattice::refined(&terrain, &refinement).unwrap();
+        assert!(lattice.ni * lattice.nj < terrain.nx * terrain.ny);
+
+        let xs: Vec<f64> = (0..lattice.ni).map(|i| lattice.node(i, 0).x).collect();
+        assert_eq!((xs[0], xs[xs.len() - 1]), (0.0, 2000.0));
+        let sizes: Vec<f64> = xs.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert!(sizes.iter().all(|size| *size > 0.0 && *size <= 150.0 * 1.5));
+        for (x, size) in xs.iter().zip(sizes.iter()) {
+            if (800.0..1200.0).contains(x) {
+                assert!(*size <= 4.0 + 1e-9);
+            }
+        }
+        // Smooth coarsening away from the zone
+        for pair in sizes.windows(2).skip_while(|pair| pair[1] <= 4.0 + 1e-9) {
+            assert!(pair[1] <= 1.2 * pair[0] * 1.5 + 1e-9);
+        }
+
+        // Ground interpolated from the raster, exact on a plane
+        for node in lattice.nodes.iter() {
+            assert!((node.z - (0.2 * node.x + 0.1 * node.y)).abs() < 1e-9);
+        }
+
+        assert!(
+            SurfaceLattice::refined(&terrain, &HorizontalRefinement::new(vec![], 1.0, 1.0))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_lattice_aligned_with_wind() {
+        let terrain = sloped_grid(101, 10.0);
+        for direction in [0.0, 30.0, 135.0, 270.0] {
+            let lattice = SurfaceLattice::aligned_with(&terrain, direction).unwrap();
+            let (cos, sin) = (
+                math::as_rads(direction).cos(),
+                math::as_rads(direction).sin(),
+            );
+
+            for node in lattice.nodes.iter() {
+                assert!((-1e-9..=1000.0 + 1e-9).contains(&node.x));
+                assert!((-1e-9..=1000.0 + 1e-9).contains(&node.y));
+                assert!((node.z - (0.2 * node.x + 0.1 * node.y)).abs() < 1e-9);
+            }
+
+            let (first, next, side) = (lattice.node(0, 0), lattice.node(1, 0), lattice.node(0, 1));
+            let along = (next.x - first.x, next.y - first.y);
+            let across = (side.x - first.x, side.y - first.y);
+            assert!((along.0 * sin - along.1 * cos).abs() < 1e-9);
+            assert!(along.0 * cos + along.1 * sin > 0.0);
+            assert!((across.0 * cos + across.1 * sin).abs() < 1e-9);
+            assert!((along.0.hypot(along.1) - across.0.hypot(across.1)).abs() < 1e-9);
+            assert!(along.0.hypot(along.1) <= 10.0);
+        }
+
+        // Unrotated, the lattice matches the raster
+        let lattice = SurfaceLattice::aligned_with(&terrain, 0.0).unwrap();
+        assert_eq!((lattice.ni, lattice.nj), (101, 101));
+    }
+
+    #[test]
+    fn test_disk_lattice() {
+        let terrain = sloped_grid(101, 10.0);
+        let lattice = SurfaceLattice::disk(&terrain, (500.0, 400.0), 300.0, 21).unwrap();
+
+        for node in lattice.nodes.iter() {
+            let distance = ((node.x - 500.0).powi(2) + (node.y - 400.0).powi(2)).sqrt();
+            assert!(distance <= 300.0 + 1e-9);
+            assert!((node.z - (0.2 * node.x + 0.1 * node.y)).abs() < 1e-9);
+        }
+        for m in 0..21 {
+            for (i, j) in [(0, m), (20, m), (m, 0), (m, 20)] {
+                let node = lattice.node(i, j);
+                let distance = ((node.x - 500.0).powi(2) + (node.y - 400.0).powi(2)).sqrt();
+                assert!((distance - 300.0).abs() < 1e-9);
+            }
+        }
+        let centre = lattice.node(10, 10);
+        assert_eq!((centre.x, centre.y, centre.z), (500.0, 400.0, 140.0));
+
+        assert!(SurfaceLattice::disk(&terrain, (100.0, 400.0), 300.0, 21).is_err());
+        assert!(SurfaceLattice::disk(&terrain, (500.0, 400.0), 300.0, 2).is_err());
+    }
+}
@@ -1,7 +1,11 @@
 use crate::math;
 use crate::{
     boundary::Grid,
+    controls::PseudoTransient,
     mesh::geometry::{self, Quad, Triangle, Vector},
+    mesh::grading::VerticalGrading,
+    mesh::lattice::SurfaceLattice,
+    sparse_system::reordering::{self, Permutation},
     sparse_system::sparse_matrix::SparseMatrix,
     sparse_system::sparse_system::SparseSystem,
 };
@@ -19,12 +23,14 @@ const AIR_MOLAR_MASS: f64 = 0.0289644;
 const PRESSURE_SEA_LEVEL: f64 = 101325.0;
 const TEMPERATURE_SEA_LEVEL: f64 = 20.0 + 273.15;
 const CALORIFIC_CAPACITY_V: f64 = 1214.0;
+const HEAT_CAPACITY_RATIO: f64 = 1.4;
 
 #[derive(Clone)]
 pub enum WallKind {
     Terrain,
     Sky,
     Inlet,
+    Outlet,
     Interior,
 }
 
@@ -70,6 +76,9 @@ pub struct Cell {
     pub physics: Physics,
     pub ground_height: f64,
     pub volume: f64,
+    pub pseudo_time_step: Option<f64>,
+    /// Number of octree splits from the initial mesh
+    pub level: usize,
 }
 
 #[derive(Clone)]
@@ -90,6 +99,9 @@ pub struct InitialPhysics {
     pub speed_ref: f64,
     pub density_ref: f64,
     pub direction: f64,
+    /// Mesh a square turned to face `direction` rather than the raster extent, see
+    /// `SurfaceLattice::aligned_with`
+    pub align_with_wind: bool,
     pub shear: f64,
     pub temperature: f64,
 }
@@ -230,6 +242,8 @@ impl Mesh {
                         physics: Physics::new(),
                         ground_height: avg_height,
                         volume,
+                        pseudo_time_step: None,
+                        level: 0,
                     });
                 }
             }
@@ -252,6 +266,10 @@ impl Mesh {
                  z
         */
         // Create walls
+        let cell_exists =
+            |i: usize, j: usize, k: usize| i < nx - 1 && j < ny - 1 && k + 1 < z_count[(i, j)];
+        let cell_id = |i: usize, j: usize, k: usize| (nx * ny) * k + ny * i + j;
+
         for i in 0..nx - 1 {
             for j in 0..ny - 1 {
                 for k in 0..z_count[(i, j)] {
@@ -266,57 +284,42 @@ impl Mesh {
                         let v6 = vertices.next().unwrap();
                         let v7 = vertices.next().unwrap();
 
-                        let (kind, neighs) = if k == 0 {
-                            (WallKind::Sky, [Some(cell.id), None])
-                        } else {
-                            let neigh_id = (nx * ny) * (k - 1) + ny * i + j;
-                            (WallKind::Interior, [Some(cell.id), Some(neigh_id)])
+                        let id = cell.id;
+                        let face = |neighbour: Option<(usize, usize, usize)>,
+                                    boundary: WallKind| {
+                            match neighbour {
+                                None => (boundary, [Some(id), None]),
+                                Some((i, j, k)) if cell_exists(i, j, k) => {
+                                    (WallKind::Interior, [Some(id), Some(cell_id(i, j, k))])
+                                }
+                                // Step of the staircase terrain
+                                Some(_) => (WallKind::Terrain, [Some(id), None]),
+                            }
                         };
+
+                        let (kind, neighs) = face((k > 0).then(|| (i, j, k - 1)), WallKind::Sky);
                         let wall_upper = Wall::new(&[&v3, &v7, &v6, &v2], kind, neighs);
                         cell.walls.push(wall_upper);
 
-                        let (kind, neighs) = if j == 0 {
-                            (WallKind::Inlet, [Some(cell.id), None])
-                        } else {
-                            let neigh_id = (nx * ny) * k + ny * i + (j - 1);
-                            (WallKind::Interior, [Some(cell.id), Some(neigh_id)])
-                        };
+                        let (kind, neighs) = face((j > 0).then(|| (i, j - 1, k)), WallKind::Inlet);
                         let wall_south = Wall::new(&[&v3, &v2, &v1, &v0], kind, neighs);
                         cell.walls.push(wall_south);
 
-                        let (kind, neighs) = if i == 0 {
-                            (WallKind::Inlet, [Some(cell.id), None])
-                        } else {
-                            let neigh_id = (nx * ny) * k + ny * (i - 1) + j;
-                            (WallKind::Interior, [Some(cell.id), Some(neigh_id)])
-                        };
+                        let (kind, neighs) = face((i > 0).then(|| (i - 1, j, k)), WallKind::Inlet);
                         let wall_west = Wall::new(&[&v0, &v4, &v7, &v3], kind, neighs);
                         cell.walls.push(wall_west);
 
-                        let (kind, neighs) = if k == z_count[(i, j)] - 1 {
-                            (WallKind::Terrain, [Some(cell.id), None])
-                        } else {
-                            let neigh_id = (nx * ny) * (k + 1) + ny * i + j;
-                            (WallKind::Interior, [Some(cell.id), Some(neigh_id)])
-                        };
+                        let (kind, neighs) = face(Some((i, j, k + 1)), WallKind::Terrain);
                         let wall_lower = Wall::new(&[&v0, &v1, &v5, &v4], kind, neighs);
                         cell.walls.push(wall_lower);
 
-                        let (kind, neighs) = if j == ny - 1 {
-                            (WallKind::Inlet, [Some(cell.id), None])
-                        } else {
-                            let neigh_id = (nx * ny) * k + ny * i + (j + 1);
-                            (WallKind::Interior, [Some(cell.id), Some(neigh_id)])
-                        };
+                        let (kind, neighs) =
+                            face((j + 2 < ny).then(|| (i, j + 1, k)), WallKind::Inlet);
                         let wall_north = Wall::new(&[&v4, &v5, &v6, &v7], kind, neighs);
                         cell.walls.push(wall_north);
 
-                        let (kind, neighs) = if i == nx - 1 {
-                            (WallKind::Inlet, [Some(cell.id), None])
-                        } else {
-                            let neigh_id = (nx * ny) * k + ny * (i + 1) + j;
-                            (WallKind::Interior, [Some(cell.id), Some(neigh_id)])
-                        };
+                        let (kind, neighs) =
+                            face((i + 2 < nx).then(|| (i + 1, j, k)), WallKind::Inlet);
                         let wall_east = Wall::new(&[&v1, &v2, &v6, &v5], kind, neighs);
                         cell.walls.push(wall_east);
                     }
@@ -344,13 +347,16 @@ impl Mesh {
                 for k in 0..z_count[(i, j)] {
                     if let Some(cell) = &mut cells[(i, j, k)] {
                         cell.id = new_idx[cell.id];
-                        for wall in cell.walls.clone().iter_mut() {
-                            for maybe_cid in wall.cells_id.iter_mut() {
-                                if let Some(cell_id) = maybe_cid {
-                                    *cell_id = new_idx[*cell_id];
-                                }
+                        for wall in cell.walls.iter_mut() {
+                            for cell_id in wall.cells_id.iter_mut().flatten() {
+                                *cell_id = new_idx[*cell_id];
                             }
                         }
+                        cell.neighbours = cell
+                            .walls
+                            .iter()
+                            .filter_map(|wall| wall.cells_id[1])
+                            .collect();
                     }
                 }
             }
@@ -360,6 +366,249 @@ impl Mesh {
         Mesh { cells: cells_mesh }
     }
 
+    /// Terrain-following mesh: every column of cells spans from the ground to `z_top`, its
+    /// vertex at relative height `sigma` being at `ground + sigma * (z_top - ground)`. The
+    /// bottom faces lie on the terrain surface. `sigmas` go from 0 to 1, strictly increasing.
+    // Superseded in main by graded_mesh, kept to compare against its stretched layers
+    #[allow(dead_code)]
+    pub fn terrain_following_mesh(
+        terrain: &Grid,
+        sigmas: &[f64],
+        z_top: f64,
+    ) -> Result<Mesh, String> {
+        if sigmas.len() < 2 || sigmas[0] != 0.0 || sigmas[sigmas.len() - 1] != 1.0 {
+            return Err("Sigma levels must go from 0 to 1".to_string());
+        }
+
+        let lattice = SurfaceLattice::from_grid(terrain);
+        if z_top <= lattice.max_ground() {
+            return Err(format!(
+                "Domain top {} is not above the highest ground {}",
+                z_top,
+                lattice.max_ground()
+            ));
+        }
+
+        Mesh::from_lattice(&lattice, sigmas.len() - 1, |node| {
+            sigmas
+                .iter()
+                .map(|sigma| node.z + sigma * (z_top - node.z))
+                .collect()
+        })
+    }
+
+    /// Terrain-following mesh with geometrically graded columns, see `VerticalGrading`. All
+    /// columns have the cell count of the deepest one, and the columns too shallow to keep
+    /// the first cell height are spaced like the deepest one.
+    pub fn graded_mesh(
+        lattice: &SurfaceLattice,
+        grading: &VerticalGrading,
+    ) -> Result<Mesh, String> {
+        // Fails when the highest ground leaves no room for the first cell
+        grading.n_layers(lattice.max_ground())?;
+        let sigmas = grading.sigmas(lattice.min_ground())?;
+        let n_layers = sigmas.len() - 1;
+        Mesh::from_lattice(lattice, n_layers, |node| {
+            grading.levels(node.z, n_layers).unwrap_or_else(|| {
+                sigmas
+                    .iter()
+                    .map(|sigma| node.z + sigma * (grading.domain_top - node.z))
+                    .collect()
+            })
+        })
+    }
+
+    /// Graded mesh of the cylinder of `radius` around `centre`, with `n` lattice nodes across,
+    /// see `SurfaceLattice::disk`. The same mesh serves every wind sector once its lateral
+    /// walls are assigned with `set_inflow_direction`.
+    // Pays off when sweeping every wind sector, main meshes for a single direction
+    #[allow(dead_code)]
+    pub fn cylindrical_mesh(
+        terrain: &Grid,
+        centre: (f64, f64),
+        radius: f64,
+        n: usize,
+        grading: &VerticalGrading,
+    ) -> Result<Mesh, String> {
+        let lattice = SurfaceLattice::disk(terrain, centre, radius, n)?;
+        Mesh::graded_mesh(&lattice, grading)
+    }
+
+    /// Lateral boundary walls facing the wind become inlets and the others outlets. The
+    /// direction is in degrees, with the convention of `InitialPhysics::direction`.
+    pub fn set_inflow_direction(&mut self, direction: f64) {
+        let wind = Vector::new(
+            math::as_rads(direction).cos(),
+            math::as_rads(direction).sin(),
+            0.0,
+        );
+
+        self.cells.par_iter_mut().for_each(|cell| {
+            let center = cell.center;
+            for wall in cell.walls.iter_mut() {
+                if matches!(wall.kind, WallKind::Inlet | WallKind::Outlet) {
+                    // Quad area vectors point into the cell
+                    let outward = match &wall.poly {
+                        Poly::Quad(quad) => geometry::quad_area_vector(&quad.vertices).scale(-1.0),
+                        Poly::Triangle(_) => wall.center.sub(&center),
+                    };
+                    // Walls parallel to the wind are outlets whatever the rounding
+                    wall.kind = if outward.dot(&wind) < -1e-9 * outward.mag() {
+                        WallKind::Inlet
+                    } else {
+                        WallKind::Outlet
+                    };
+                }
+            }
+        })
+    }
+
+    /// Hexahedral mesh with one column of `n_layers` cells per quad of the lattice. `levels`
+    /// gives the `n_layers + 1` vertex heights above each lattice node, from the ground up.
+    /// Cells of a column are numbered consecutively from the ground.
+    pub fn from_lattice(
+        lattice: &SurfaceLattice,
+        n_layers: usize,
+        levels: impl Fn(&Vector) -> Vec<f64>,
+    ) -> Result<Mesh, String> {
+        let (ni, nj) = (lattice.ni, lattice.nj);
+        if ni < 2 || nj < 2 || n_layers == 0 {
+            return Err(format!(
+                "Cannot mesh a {}x{} lattice with {} layers",
+                ni, nj, n_layers
+            ));
+        }
+
+        let heights: Vec<Vec<f64>> = lattice.nodes.iter().map(&levels).collect();
+        let heights = Array2::from_shape_vec((ni, nj), heights).unwrap();
+        for ((i, j), column) in heights.indexed_iter() {
+            if column.len() != n_layers + 1 || column.windows(2).any(|pair| pair[1] <= pair[0]) {
+                return Err(format!(
+                    "Levels at node ({}, {}) are not {} increasing heights",
+                    i,
+                    j,
+                    n_layers + 1
+                ));
+            }
+        }
+
+        let cell_id = |i: usize, j: usize, k: usize| (i * (nj - 1) + j) * n_layers + k;
+        let vertex = |i: usize, j: usize, k: usize| {
+            let node = lattice.node(i, j);
+            Vector::new(node.x, node.y, heights[[i, j]][k])
+        };
+
+        let cells = (0..lattice.n_columns() * n_layers)
+            .into_par_iter()
+            .map(|id| {
+                let k = id % n_layers;
+                let (i, j) = ((id / n_layers) / (nj - 1), (id / n_layers) % (nj - 1));
+
+                let v0 = vertex(i, j, k);
+                let v1 = vertex(i + 1, j, k);
+                let v2 = vertex(i + 1, j, k + 1);
+                let v3 = vertex(i, j, k + 1);
+                let v4 = vertex(i, j + 1, k);
+                let v5 = vertex(i + 1, j + 1, k);
+                let v6 = vertex(i + 1, j + 1, k + 1);
+                let v7 = vertex(i, j + 1, k + 1);
+
+                let face = |neighbour: Option<(usize, usize, usize)>, boundary: WallKind| {
+                    match neighbour {
+                        Some((i, j, k)) => (WallKind::Interior, [Some(id), Some(cell_id(i, j, k))]),
+                        None => (boundary, [Some(id), None]),
+                    }
+                };
+
+                let (kind, neighs) = face((k + 1 < n_layers).then(|| (i, j, k + 1)), WallKind::Sky);
+                let wall_upper = Wall::new(&[&v3, &v7, &v6, &v2], kind, neighs);
+                let (kind, neighs) = face((j > 0).then(|| (i, j - 1, k)), WallKind::Inlet);
+                let wall_south = Wall::new(&[&v3, &v2, &v1, &v0], kind, neighs);
+                let (kind, neighs) = face((i > 0).then(|| (i - 1, j, k)), WallKind::Inlet);
+                let wall_west = Wall::new(&[&v0, &v4, &v7, &v3], kind, neighs);
+                let (kind, neighs) = face((k > 0).then(|| (i, j, k - 1)), WallKind::Terrain);
+                let wall_lower = Wall::new(&[&v0, &v1, &v5, &v4], kind, neighs);
+                let (kind, neighs) = face((j + 2 < nj).then(|| (i, j + 1, k)), WallKind::Inlet);
+                let wall_north = Wall::new(&[&v4, &v5, &v6, &v7], kind, neighs);
+                let (kind, neighs) = face((i + 2 < ni).then(|| (i + 1, j, k)), WallKind::Inlet);
+                let wall_east = Wall::new(&[&v1, &v2, &v6, &v5], kind, neighs);
+
+                let walls = vec![
+                    wall_upper, wall_south, wall_west, wall_lower, wall_north, wall_east,
+                ];
+                let neighbours = walls.iter().filter_map(|wall| wall.cells_id[1]).collect();
+                let vertices = vec![v0, v1, v2, v3, v4, v5, v6, v7];
+                let ground_height = [(i, j), (i + 1, j), (i, j + 1), (i + 1, j + 1)]
+                    .iter()
+                    .map(|(i, j)| lattice.node(*i, *j).z)
+                    .sum::<f64>()
+                    / 4.0;
+
+                Cell {
+                    id,
+                    center: geometry::average_points(&vertices),
+                    volume: geometry::hexahedron_volume(&vertices),
+                    vertices,
+                    walls,
+                    neighbours,
+                    physics: Physics::new(),
+                    ground_height,
+                    pseudo_time_step: None,
+                    level: 0,
+                }
+            })
+            .collect();
+
+        Ok(Mesh { cells })
+    }
+
+    /// Cells sharing an interior wall with each cell
+    pub fn adjacency(&self) -> Vec<Vec<usize>> {
+        self.cells
+            .iter()
+            .map(|cell| cell.neighbours.clone())
+            .collect()
+    }
+
+    /// Reverse Cuthill-McKee numbering of the cells, which keeps the systems assembled on the
+    /// mesh close to the diagonal
+    pub fn rcm_permutation(&self) -> Permutation {
+        reordering::reverse_cuthill_mckee(&self.adjacency())
+    }
+
+    /// Moves every cell to its new index and updates the ids stored in cells and walls
+    pub fn renumber(&mut self, permutation: &Permutation) -> Result<(), String> {
+        if permutation.len() != self.cells.len() {
+            return Err(format!(
+                "Cannot renumber {} cells with a permutation of {} indices",
+                self.cells.len(),
+                permutation.len()
+            ));
+        }
+
+        let mut old_cells: Vec<Option<Cell>> = self.cells.drain(..).map(Some).collect();
+        self.cells = (0..permutation.len())
+            .map(|new| old_cells[permutation.old_index(new)].take().unwrap())
+            .collect();
+
+        self.cells
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(new, cell)| {
+                cell.id = new;
+                for wall in cell.walls.iter_mut() {
+                    for cell_id in wall.cells_id.iter_mut().flatten() {
+                        *cell_id = permutation.new_index(*cell_id);
+                    }
+                }
+                for neighbour in cell.neighbours.iter_mut() {
+                    *neighbour = permutation.new_index(*neighbour);
+                }
+            });
+
+        Ok(())
+    }
+
     pub fn save_to_vtk(&self, filename: impl AsRef<Path>) -> Result<(), std::io::Error> {
         let file = File::create(filename)?;
         let mut file = BufWriter::new(file);
@@ -475,7 +724,292 @@ impl Mesh {
         })
     }
 
+    pub fn update_pseudo_time_steps(&mut self, pseudo_transient: Option<&PseudoTransient>) {
+        let Some(pseudo) = pseudo_transient else {
+            self.cells
+                .par_iter_mut()
+                .for_each(|cell| cell.pseudo_time_step = None);
+            return;
+        };
+
+        let gas_constant = UNIVERSAL_GAS_CONSTANT / AIR_MOLAR_MASS;
+
+        self.cells.par_iter_mut().for_each(|cell| {
+            // Acoustic CFL condition: dt = CFL * dx / (|u| + c)
+            let length = cell.volume.abs().cbrt();
+            let sound_speed =
+                (HEAT_CAPACITY_RATIO * gas_constant * cell.physics.temperature.max(0.0)).sqrt();
+            let wave_speed = cell.physics.velocity.mag() + sound_speed;
+
+            let dt = if wave_speed > 0.0 {
+                pseudo.cfl * length / wave_speed
+            } else {
+                pseudo.max_time_step
+            };
+
+            cell.pseudo_time_step = Some(dt.clamp(pseudo.min_time_step, pseudo.max_time_step));
+        })
+    }
+
     pub fn make_system(&self) -> SparseSystem {
         todo!();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bumpy_grid(n: usize) -> Grid {
+        let elevations = Array2::from_shape_fn((n, n), |(i, j)| ((i * j) % 5) as f64 * 10.0);
+        Grid {
+            elevations,
+            x_min: 0.0,
+            y_min: 0.0,
+            x_max: (n - 1) as f64 * 10.0,
+            y_max: (n - 1) as f64 * 10.0,
+            x_res: 10.0,
+            y_res: 10.0,
+            z_min: 0.0,
+            z_max: 40.0,
+            nx: n,
+            ny: n,
+        }
+    }
+
+    fn assert_consistent(mesh: &Mesh) {
+        for (index, cell) in mesh.cells.iter().enumerate() {
+            assert_eq!(cell.id, index);
+            for wall in cell.walls.iter() {
+                assert_eq!(wall.cells_id[0], Some(cell.id));
+                if let Some(other) = wall.cells_id[1] {
+                    assert!(mesh.cells[other].neighbours.contains(&cell.id));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_terrain_following_mesh() {
+        let terrain = bumpy_grid(9);
+        let sigmas = math::linspace(0.0, 1.0, 5);
+        let mesh = Mesh::terrain_following_mesh(&terrain, &sigmas, 200.0).unwrap();
+        assert_consistent(&mesh);
+        assert_eq!(mesh.cells.len(), 8 * 8 * 4);
+
+        // Bottom faces on the terrain, no staircase steps inside the domain
+        for cell in mesh.cells.iter() {
+            for wall in cell.walls.iter() {
+                if let (WallKind::Terrain, Poly::Quad(quad)) = (&wall.kind, &wall.poly) {
+                    for vertex in quad.vertices.iter() {
+                        let i = (vertex.x / terrain.x_res).round() as usize;
+                        let j = (vertex.y / terrain.y_res).round() as usize;
+                        assert_eq!(vertex.z, terrain.z(i, j));
+                    }
+                }
+            }
+        }
+        let terrain_walls = mesh
+            .cells
+            .iter()
+            .flat_map(|cell| cell.walls.iter())
+            .filter(|wall| matches!(wall.kind, WallKind::Terrain))
+            .count();
+        assert_eq!(terrain_walls, 8 * 8);
+
+        // Columns with vertical sides and a flat top: volume is area * (top - mean ground)
+        let total: f64 = mesh.cells.iter().map(|cell| cell.volume).sum();
+        let expected: f64 = (0..8)
+            .flat_map(|i| (0..8).map(move |j| (i, j)))
+            .map(|(i, j)| {
+                let ground = (terrain.z(i, j)
+                    + terrain.z(i + 1, j)
+                    + terrain.z(i, j + 1)
+                    + terrain.z(i + 1, j + 1))
+                    / 4.0;
+                100.0 * (200.0 - ground)
+            })
+            .sum();
+        assert!((total - expected).abs() < 1e-6 * expected);
+
+        assert!(Mesh::terrain_following_mesh(&terrain, &sigmas, 30.0).is_err());
+        assert!(Mesh::terrain_following_mesh(&terrain, &[0.0, 0.6, 0.5, 1.0], 200.0).is_err());
+    }
+
+    #[test]
+    fn test_graded_mesh() {
+        let lattice = SurfaceLattice::from_grid(&bumpy_grid(9));
+        let grading = VerticalGrading::new(1.5, 1.25, 300.0).unwrap();
+        let mesh = Mesh::graded_mesh(&lattice, &grading).unwrap();
+        assert_consistent(&mesh);
+
+        for cell in mesh.cells.iter() {
+            let on_ground = cell
+                .walls
+                .iter()
+                .any(|wall| matches!(wall.kind, WallKind::Terrain));
+            if on_ground {
+                for (bottom, top) in [(0, 3), (1, 2), (4, 7), (5, 6)] {
+                    let height = cell.vertices[top].z - cell.vertices[bottom].z;
+                    assert!((height - 1.5).abs() < 1e-9);
+                }
+            }
+        }
+
+        let too_low = VerticalGrading::new(1.5, 1.25, 41.0).unwrap();
+        assert!(Mesh::graded_mesh(&lattice, &too_low).is_err());
+    }
+
+    #[test]
+    fn test_cylindrical_mesh() {
+        let grading = VerticalGrading::new(2.0, 1.3, 200.0).unwrap();
+        let mut mesh =
+            Mesh::cylindrical_mesh(&bumpy_grid(21), (100.0, 100.0), 80.0, 11, &grading).unwrap();
+        assert_consistent(&mesh);
+        assert!(mesh.cells.iter().all(|cell| cell.volume > 0.0));
+
+        let lateral = |mesh: &Mesh| -> Vec<(Vector, bool)> {
+            mesh.cells
+                .iter()
+                .flat_map(|cell| cell.walls.iter())
+                .filter_map(|wall| match wall.kind {
+                    WallKind::Inlet => Some((wall.center, true)),
+                    WallKind::Outlet => Some((wall.center, false)),
+                    _ => None,
+                })
+                .collect()
+        };
+        for (center, _) in lateral(&mesh) {
+            let distance = ((center.x - 100.0).powi(2) + (center.y - 100.0).powi(2)).sqrt();
+            assert!(distance > 75.0 && distance <= 80.0);
+        }
+
+        // Flow towards +x enters on the west half, towards +y on the south half
+        for (direction, axis) in [(0.0, 0), (90.0, 1)] {
+            mesh.set_inflow_direction(direction);
+            let walls = lateral(&mesh);
+            assert!(walls.iter().any(|(_, inlet)| *inlet));
+            assert!(walls.iter().any(|(_, inlet)| !*inlet));
+            for (center, inlet) in walls {
+                assert_eq!(inlet, [center.x, center.y][axis] < 100.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_wind_aligned_mesh() {
+        let lattice = SurfaceLattice::aligned_with(&bumpy_grid(13), 40.0).unwrap();
+        let grading = VerticalGrading::new(2.0, 1.3, 200.0).unwrap();
+        let mut mesh = Mesh::graded_mesh(&lattice, &grading).unwrap();
+        assert_consistent(&mesh);
+        let n_layers = mesh.cells.len() / lattice.n_columns();
+
+        mesh.set_inflow_direction(40.0);
+        let first_column = lattice.node(0, 0);
+        for cell in mesh.cells.iter() {
+            for wall in cell.walls.iter() {
+                if matches!(wall.kind, WallKind::Inlet) {
+                    let offset = wall.center.sub(first_column);
+                    let along = offset.dot(&Vector::new(
+                        math::as_rads(40.0).cos(),
+                        math::as_rads(40.0).sin(),
+                        0.0,
+                    ));
+                    assert!(along.abs() < 1e-9);
+                }
+            }
+        }
+        let inlets = mesh
+            .cells
+            .iter()
+            .flat_map(|cell| cell.walls.iter())
+            .filter(|wall| matches!(wall.kind, WallKind::Inlet))
+            .count();
+        assert_eq!(inlets, (lattice.nj - 1) * n_layers);
+    }
+
+    #[test]
+    fn test_staircase_wall_kinds() {
+        let zs = math::linspace(-10.0, 100.0, 12);
+        let mesh = Mesh::naive_mesh(&bumpy_grid(9), zs);
+        assert_consistent(&mesh);
+
+        let on_side = |wall: &Wall| {
+            [wall.center.x, wall.center.y]
+                .iter()
+                .any(|c| c.abs() < 1e-9 || (c - 80.0).abs() < 1e-9)
+        };
+        let mut steps = 0;
+        for cell in mesh.cells.iter() {
+            for (side, wall) in cell.walls.iter().enumerate() {
+                let lateral = side != 0 && side != 3;
+                match wall.kind {
+                    WallKind::Interior => assert!(wall.cells_id[1].is_some()),
+                    WallKind::Inlet | WallKind::Outlet => assert!(lateral && on_side(wall)),
+                    WallKind::Sky => assert_eq!(side, 0),
+                    WallKind::Terrain if lateral => steps += 1,
+                    WallKind::Terrain => assert_eq!(side, 3),
+                }
+                if wall.cells_id[1].is_none() && lateral && on_side(wall) {
+                    assert!(matches!(wall.kind, WallKind::Inlet));
+                }
+            }
+        }
+        assert!(steps > 0);
+
+        let count = |kind: fn(&WallKind) -> bool| {
+            mesh.cells
+                .iter()
+                .flat_map(|cell| cell.walls.iter())
+                .filter(|wall| kind(&wall.kind))
+                .count()
+        };
+        assert_eq!(count(|kind| matches!(kind, WallKind::Sky)), 8 * 8);
+        assert_eq!(
+            count(|kind| matches!(kind, WallKind::Terrain)),
+            8 * 8 + steps
+        );
+    }
+
+    #[test]
+    fn test_inflow_direction_parallel_walls() {
+        let lattice = SurfaceLattice::from_grid(&bumpy_grid(9));
+        let grading = VerticalGrading::new(2.0, 1.3, 200.0).unwrap();
+        let mut mesh = Mesh::graded_mesh(&lattice, &grading).unwrap();
+
+        // The wind vector is off the axes by rounding, walls along it must not flip to inlets
+        for (direction, upwind) in [(90.0, (None, Some(0.0))), (180.0, (Some(80.0), None))] {
+            mesh.set_inflow_direction(direction);
+            for wall in mesh.cells.iter().flat_map(|cell| cell.walls.iter()) {
+                let facing = |side: Option<f64>, coordinate: f64| {
+                    side.is_some_and(|side| (coordinate - side).abs() < 1e-9)
+                };
+                let inlet = facing(upwind.0, wall.center.x) || facing(upwind.1, wall.center.y);
+                match wall.kind {
+                    WallKind::Inlet => assert!(inlet),
+                    WallKind::Outlet => assert!(!inlet),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rcm_renumbering() {
+        let zs = math::linspace(-10.0, 100.0, 6);
+        let mut mesh = Mesh::naive_mesh(&bumpy_grid(9), zs);
+        assert_consistent(&mesh);
+        assert!(mesh.cells.iter().any(|cell| cell.neighbours.len() < 6));
+
+        let volumes: Vec<f64> = mesh.cells.iter().map(|cell| cell.volume).collect();
+        let before = reordering::bandwidth(&mesh.adjacency());
+        let permutation = mesh.rcm_permutation();
+        mesh.renumber(&permutation).unwrap();
+        assert_consistent(&mesh);
+
+        let after = reordering::bandwidth(&mesh.adjacency());
+        assert!(after < before, "{} >= {}", after, before);
+        let moved: Vec<f64> = mesh.cells.iter().map(|cell| cell.volume).collect();
+        assert_eq!(moved, permutation.apply(&volumes));
+    }
+}
@@ -1,2 +1,7 @@
+pub mod adaptation;
 pub mod geometry;
+pub mod grading;
+pub mod hierarchy;
+pub mod lattice;
 pub mod mesher;
+pub mod refinement;
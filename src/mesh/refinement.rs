@@ -0,0 +1,485 @@
+use crate::mesh::{
+    geometry::{self, Vector},
+    mesher::{Cell, Mesh, Physics, Poly, Wall, WallKind},
+};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Vertices of every side of a hexahedral `Cell`, in the order the meshers create the walls:
+/// upper, south, west, lower, north, east. Their area vectors point into the cell.
+const SIDES: [[usize; 4]; 6] = [
+    [3, 7, 6, 2],
+    [3, 2, 1, 0],
+    [0, 4, 7, 3],
+    [0, 1, 5, 4],
+    [4, 5, 6, 7],
+    [1, 2, 6, 5],
+];
+
+/// Bit exact identity of a face, whatever the order of its vertices
+type FaceKey = [[u64; 3]; 4];
+
+/// Why a cell should be split
+// Built by the callers of mark_cells, which main has none of yet
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum RefinementCriterion {
+    /// Cells on the ground where the terrain is steeper than this slope (rise over run)
+    TerrainSlope(f64),
+    /// Cells whose centre is closer than `radius` to a site such as a turbine hub
+    DistanceToSites { sites: Vec<Vector>, radius: f64 },
+    /// Cells where the norm of the velocity gradient, see `Mesh::gradient`, exceeds this value
+    VelocityGradient(f64),
+}
+
+fn face_key(points: &[Vector; 4]) -> FaceKey {
+    let mut key = points.map(|p| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]);
+    key.sort_unstable();
+    key
+}
+
+fn midpoint(a: &Vector, b: &Vector) -> Vector {
+    a.add(b).div(2.0)
+}
+
+/// Centre of a quad summed along its diagonals, so the neighbours on both sides of a face
+/// get the same bits whatever the order they list its vertices in
+fn face_centre(points: &[Vector; 4]) -> Vector {
+    points[0]
+        .add(&points[2])
+        .add(&points[1].add(&points[3]))
+        .div(4.0)
+}
+
+/// The four quarters of a face, with its orientation
+fn sub_faces(points: &[Vector; 4]) -> [[Vector; 4]; 4] {
+    let [a, b, c, d] = *points;
+    let (ab, bc, cd, da) = (
+        midpoint(&a, &b),
+        midpoint(&b, &c),
+        midpoint(&c, &d),
+        midpoint(&d, &a),
+    );
+    let m = face_centre(points);
+    [
+        [a, ab, m, da],
+        [ab, b, bc, m],
+        [m, bc, c, cd],
+        [da, m, cd, d],
+    ]
+}
+
+fn side_points(cell: &Cell, side: usize) -> [Vector; 4] {
+    SIDES[side].map(|v| cell.vertices[v])
+}
+
+pub(crate) fn wall_points(wall: &Wall) -> Option<[Vector; 4]> {
+    match &wall.poly {
+        Poly::Quad(quad) => Some(quad.vertices),
+        Poly::Triangle(_) => None,
+    }
+}
+
+/// Walls of `cell` lying on `side`, either the whole side or a quarter of it
+fn walls_on_side(cell: &Cell, side: usize) -> Vec<&Wall> {
+    let points = side_points(cell, side);
+    let mut keys = vec![face_key(&points)];
+    keys.extend(sub_faces(&points).iter().map(face_key));
+
+    cell.walls
+        .iter()
+        .filter(|wall| wall_points(wall).is_some_and(|p| keys.contains(&face_key(&p))))
+        .collect()
+}
+
+/// The 8 children of a hexahedron, splitting it at the midpoints of its trilinear mapping
+fn split(cell: &Cell) -> Vec<Cell> {
+    // Lattice point (x, y, z) in 0..3 along v0->v1, v0->v4 and v0->v3
+    let corner = |x: usize, y: usize, z: usize| {
+        let index = match (x, y, z) {
+            (0, 0, 0) => 0,
+            (2, 0, 0) => 1,
+            (2, 0, 2) => 2,
+            (0, 0, 2) => 3,
+            (0, 2, 0) => 4,
+            (2, 2, 0) => 5,
+            (2, 2, 2) => 6,
+            _ => 7,
+        };
+        cell.vertices[index]
+    };
+    let ends = |c: usize| if c == 1 { [0, 2] } else { [c, c] };
+
+    let point = |x: usize, y: usize, z: usize| {
+        let (xs, ys, zs) = (ends(x), ends(y), ends(z));
+        match [x, y, z].iter().filter(|c| **c == 1).count() {
+            0 | 1 => midpoint(&corner(xs[0], ys[0], zs[0]), &corner(xs[1], ys[1], zs[1])),
+            2 => {
+                // Corners of the face in cyclic order around it
+                let corners: Vec<Vector> = [(0, 0), (1, 0), (1, 1), (0, 1)]
+                    .iter()
+                    .map(|(s, t)| match (x == 1, y == 1) {
+                        (true, true) => corner(xs[*s], ys[*t], z),
+                        (true, false) => corner(xs[*s], y, zs[*t]),
+                        _ => corner(x, ys[*s], zs[*t]),
+                    })
+                    .collect();
+                face_centre(&[corners[0], corners[1], corners[2], corners[3]])
+            }
+            _ => geometry::average_points(&cell.vertices),
+        }
+    };
+
+    let mut points = [[[Vector::new(0.0, 0.0, 0.0); 3]; 3]; 3];
+    for (x, plane) in points.iter_mut().enumerate() {
+        for (y, row) in plane.iter_mut().enumerate() {
+            for (z, p) in row.iter_mut().enumerate() {
+                *p = point(x, y, z);
+            }
+        }
+    }
+
+    let mut children = Vec::with_capacity(8);
+    for a in 0..2 {
+        for b in 0..2 {
+            for c in 0..2 {
+                let vertices = vec![
+                    points[a][b][c],
+                    points[a + 1][b][c],
+                    points[a + 1][b][c + 1],
+                    points[a][b][c + 1],
+                    points[a][b + 1][c],
+                    points[a + 1][b + 1][c],
+                    points[a + 1][b + 1][c + 1],
+                    points[a][b + 1][c + 1],
+                ];
+                children.push(Cell {
+                    id: cell.id,
+                    center: geometry::average_points(&vertices),
+                    volume: geometry::hexahedron_volume(&vertices),
+                    vertices,
+                    walls: Vec::with_capacity(6),
+                    neighbours: Vec::with_capacity(6),
+                    physics: cell.physics.clone(),
+                    ground_height: cell.ground_height,
+                    pseudo_time_step: None,
+                    level: cell.level + 1,
+                });
+            }
+        }
+    }
+    children
+}
+
+impl Mesh {
+    /// Cells meeting any of the criteria, among those split less than `max_level` times
+    // Refinement up front, main gets its resolution from the graded lattice and adapt marks
+    // cells from the error indicators instead
+    #[allow(dead_code)]
+    pub fn mark_cells(&self, criteria: &[RefinementCriterion], max_level: usize) -> Vec<bool> {
+        let mut marked: Vec<bool> = self
+            .cells
+            .iter()
+            .map(|cell| cell.level < max_level)
+            .collect();
+        let mut meets = vec![false; self.cells.len()];
+
+        for criterion in criteria.iter() {
+            let hits: Vec<bool> = match criterion {
+                RefinementCriterion::TerrainSlope(max_slope) => self
+                    .cells
+                    .iter()
+                    .map(|cell| {
+                        cell.walls
+                            .iter()
+                            .filter(|wall| matches!(wall.kind, WallKind::Terrain))
+                            .filter_map(wall_points)
+                            .any(|points| {
+                                let normal = geometry::quad_area_vector(&points);
+                                normal.x.hypot(normal.y) > max_slope * normal.z.abs()
+                            })
+                    })
+                    .collect(),
+                RefinementCriterion::DistanceToSites { sites, radius } => self
+                    .cells
+                    .iter()
+                    .map(|cell| {
+                        sites
+                            .iter()
+                            .any(|site| cell.center.sub(site).mag() < *radius)
+                    })
+                    .collect(),
+                RefinementCriterion::VelocityGradient(threshold) => self
+                    .velocity_gradient_norms()
+                    .iter()
+                    .map(|gradient| gradient > threshold)
+                    .collect(),
+            };
+            meets.iter_mut().zip(hits).for_each(|(m, hit)| *m |= hit);
+        }
+
+        marked.iter_mut().zip(meets).for_each(|(m, hit)| *m &= hit);
+        marked
+    }
+
+    /// Extends the marks so neighbouring cells never differ by more than one level
+    pub(crate) fn balance(&self, marked: &mut [bool]) {
+        let mut pending: Vec<usize> = (0..marked.len()).filter(|id| marked[*id]).collect();
+        while let Some(id) = pending.pop() {
+            let level = self.cells[id].level;
+            for neighbour in self.cells[id].neighbours.iter() {
+                if !marked[*neighbour] && self.cells[*neighbour].level < level {
+                    marked[*neighbour] = true;
+                    pending.push(*neighbour);
+                }
+            }
+        }
+    }
+
+    /// Splits the marked cells in 8, plus the ones needed to keep the 2:1 balance. A side
+    /// facing two times smaller cells gets one wall per neighbour (hanging face). Children
+    /// take the place of their parent in the numbering and its physics. Returns the index
+    /// in the old mesh of the cell each new cell comes from.
+    pub fn refine(&mut self, marked: &[bool]) -> Result<Vec<usize>, String> {
+        if marked.len() != self.cells.len() {
+            return Err(format!(
+                "{} refinement marks for {} cells",
+                marked.len(),
+                self.cells.len()
+            ));
+        }
+        if let Some(cell) = self.cells.iter().find(|cell| cell.vertices.len() != 8) {
+            return Err(format!("Cell {} is not a hexahedron", cell.id));
+        }
+
+        let mut marked = marked.to_vec();
+        self.balance(&mut marked);
+
+        let old_cells = std::mem::take(&mut self.cells);
+        let mut parents = Vec::with_capacity(old_cells.len());
+        let mut new_index = vec![None; old_cells.len()];
+        for (old, cell) in old_cells.iter().enumerate() {
+            if marked[old] {
+                for child in split(cell) {
+                    parents.push(old);
+                    self.cells.push(child);
+                }
+            } else {
+                new_index[old] = Some(self.cells.len());
+                parents.push(old);
+                self.cells.push(cell.clone());
+            }
+        }
+
+        // Cells next to a split one, or split themselves, get new walls
+        let affected: Vec<bool> = parents
+            .iter()
+            .map(|old| marked[*old] || old_cells[*old].neighbours.iter().any(|n| marked[*n]))
+            .collect();
+
+        // Both cells of a conforming face register it
+        let mut faces: HashMap<FaceKey, Vec<usize>> = HashMap::new();
+        let mut quarters: HashMap<FaceKey, usize> = HashMap::new();
+        for (id, cell) in self.cells.iter().enumerate() {
+            for side in 0..SIDES.len() {
+                let points = side_points(cell, side);
+                faces.entry(face_key(&points)).or_default().push(id);
+                for quarter in sub_faces(&points).iter() {
+                    quarters.insert(face_key(quarter), id);
+                }
+            }
+        }
+
+        let walls: Vec<Option<Vec<Wall>>> = (0..self.cells.len())
+            .into_par_iter()
+            .map(|id| {
+                if !affected[id] {
+                    return None;
+                }
+                let cell = &self.cells[id];
+                let source = &old_cells[parents[id]];
+                let mut walls = Vec::with_capacity(6);
+
+                for side in 0..SIDES.len() {
+                    let points = side_points(cell, side);
+                    let old_walls = walls_on_side(source, side);
+                    let physics = old_walls
+                        .first()
+                        .map_or_else(Physics::new, |wall| wall.physics.clone());
+                    let mut push = |points: &[Vector; 4], kind: WallKind, other: Option<usize>| {
+                        let mut wall =
+                            Wall::new(&points.iter().collect::<Vec<_>>(), kind, [Some(id), other]);
+                        wall.physics = physics.clone();
+                        walls.push(wall);
+                    };
+
+                    let key = face_key(&points);
+                    let same_size = faces
+                        .get(&key)
+                        .and_then(|ids| ids.iter().find(|other| **other != id));
+                    if let Some(other) = same_size {
+                        push(&points, WallKind::Interior, Some(*other));
+                    } else if let Some(coarser) = quarters.get(&key).filter(|other| **other != id) {
+                        push(&points, WallKind::Interior, Some(*coarser));
+                    } else {
+                        let pieces = sub_faces(&points);
+                        let finer: Vec<Option<usize>> = pieces
+                            .iter()
+                            .map(|q| faces.get(&face_key(q)).map(|ids| ids[0]))
+                            .collect();
+                        if finer.iter().all(|f| f.is_some()) {
+                            for (quarter, fine) in pieces.iter().zip(finer) {
+                                push(quarter, WallKind::Interior, fine);
+                            }
+                        } else {
+                            let kind = old_walls
+                                .iter()
+                                .find(|wall| wall.cells_id[1].is_none())
+                                .map_or(WallKind::Terrain, |wall| wall.kind.clone());
+                            push(&points, kind, None);
+                        }
+                    }
+                }
+                Some(walls)
+            })
+            .collect();
+
+        for (id, (cell, new_walls)) in self.cells.iter_mut().zip(walls).enumerate() {
+            cell.id = id;
+            match new_walls {
+                Some(walls) => cell.walls = walls,
+                None => {
+                    for wall in cell.walls.iter_mut() {
+                        for cell_id in wall.cells_id.iter_mut().flatten() {
+                            *cell_id = new_index[*cell_id].unwrap();
+                        }
+                    }
+                }
+            }
+            cell.neighbours = cell
+                .walls
+                .iter()
+                .filter_map(|wall| wall.cells_id[1])
+                .collect();
+        }
+
+        Ok(parents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::lattice::SurfaceLattice;
+    use ndarray::Array2;
+
+    fn small_mesh() -> Mesh {
+        let nodes = Array2::from_shape_fn((3, 3), |(i, j)| {
+            Vector::new(10.0 * i as f64, 10.0 * j as f64, (i * j) as f64)
+        });
+        let lattice = SurfaceLattice {
+            nodes,
+            ni: 3,
+            nj: 3,
+        };
+        Mesh::from_lattice(&lattice, 2, |node| vec![node.z, 8.0, 20.0]).unwrap()
+    }
+
+    fn assert_closed_and_symmetric(mesh: &Mesh) {
+        for (id, cell) in mesh.cells.iter().enumerate() {
+            assert_eq!(cell.id, id);
+            let closure = cell
+                .walls
+                .iter()
+                .fold(Vector::new(0.0, 0.0, 0.0), |sum, wall| {
+                    sum.add(&geometry::quad_area_vector(&wall_points(wall).unwrap()))
+                });
+            assert!(closure.mag() < 1e-9, "cell {} is not closed", id);
+
+            for wall in cell.walls.iter() {
+                if let Some(other) = wall.cells_id[1] {
+                    let back = mesh.cells[other]
+                        .walls
+                        .iter()
+                        .filter(|w| w.cells_id[1] == Some(id))
+                        .count();
+                    assert_eq!(back, 1);
+                    assert!(cell.level.abs_diff(mesh.cells[other].level) <= 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_refinement_with_hanging_faces() {
+        let mut mesh = small_mesh();
+        let volume: f64 = mesh.cells.iter().map(|cell| cell.volume).sum();
+        assert_closed_and_symmetric(&mesh);
+
+        let mut marked = vec![false; mesh.cells.len()];
+        marked[0] = true;
+        let parents = mesh.refine(&marked).unwrap();
+        assert_eq!(mesh.cells.len(), 15);
+        assert_eq!(parents[..9], [0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_closed_and_symmetric(&mesh);
+        let refined: f64 = mesh.cells.iter().map(|cell| cell.volume).sum();
+        assert!((refined - volume).abs() < 1e-9 * volume);
+
+        // The coarse cell above the split one sees four cells through its lower side
+        let above = &mesh.cells[8];
+        assert_eq!(above.level, 0);
+        assert_eq!(above.walls.len(), 9);
+        let terrain = mesh.cells[..8]
+            .iter()
+            .flat_map(|cell| cell.walls.iter())
+            .filter(|wall| matches!(wall.kind, WallKind::Terrain))
+            .count();
+        assert_eq!(terrain, 4);
+
+        // Splitting a child again forces its coarse neighbours to follow
+        let mut marked = vec![false; mesh.cells.len()];
+        marked[7] = true;
+        mesh.refine(&marked).unwrap();
+        assert_closed_and_symmetric(&mesh);
+        assert!(mesh.cells.iter().any(|cell| cell.level == 2));
+        let refined: f64 = mesh.cells.iter().map(|cell| cell.volume).sum();
+        assert!((refined - volume).abs() < 1e-9 * volume);
+    }
+
+    #[test]
+    fn test_refinement_criteria() {
+        let mesh = small_mesh();
+        let near_site = RefinementCriterion::DistanceToSites {
+            sites: vec![Vector::new(15.0, 15.0, 15.0)],
+            radius: 5.0,
+        };
+        let marked = mesh.mark_cells(std::slice::from_ref(&near_site), 3);
+        assert_eq!(marked.iter().filter(|m| **m).count(), 1);
+        assert!(mesh.mark_cells(&[near_site], 0).iter().all(|m| !m));
+
+        // Only the columns with a sloped ground
+        let marked = mesh.mark_cells(&[RefinementCriterion::TerrainSlope(0.1)], 3);
+        assert_eq!(marked.iter().filter(|m| **m).count(), 3);
+
+        // Uniform shear of 0.5 1/s, walls included
+        let mut mesh = mesh;
+        for cell in mesh.cells.iter_mut() {
+            cell.physics.velocity.x = 0.5 * cell.center.z;
+            for wall in cell.walls.iter_mut() {
+                wall.physics.velocity.x = 0.5 * wall.center.z;
+            }
+        }
+        let count = |threshold: f64| {
+            let criteria = [RefinementCriterion::VelocityGradient(threshold)];
+            mesh.mark_cells(&criteria, 3).iter().filter(|m| **m).count()
+        };
+        // Green-Gauss is not exact on the uneven layers, face values average the centres
+        let norms = mesh.velocity_gradient_norms();
+        assert!(norms.iter().all(|norm| (norm - 0.5).abs() < 0.2));
+        assert_eq!(count(0.2), mesh.cells.len());
+        assert_eq!(count(0.8), 0);
+        let threshold = norms[0];
+        let steeper = norms.iter().filter(|norm| **norm > threshold).count();
+        assert_eq!(count(threshold), steeper);
+    }
+}
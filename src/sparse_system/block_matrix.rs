@@ -0,0 +1,350 @@
+use crate::sparse_system::sparse_matrix::{MatrixRows, SparseMatrix};
+use rayon::prelude::*;
+
+/// Block compressed sparse row storage: one dense `block_size` x `block_size` block per
+/// coupled pair of cells, e.g. the u, v, w, p unknowns of a cell and its neighbours.
+/// Scalar unknown `cell * block_size + variable` is the row and column numbering seen by
+/// the solvers.
+#[derive(Clone, Debug)]
+pub struct BlockSparseMatrix {
+    pub block_size: usize,
+    pub n_block_rows: usize,
+    pub n_block_cols: usize,
+    pub row_ptr: Vec<usize>,
+    pub block_cols: Vec<usize>,
+    /// Row-major blocks, `block_size * block_size` values each
+    pub values: Vec<f64>,
+}
+
+/// (block row, block col, position within the block, value)
+type BlockEntry = (usize, usize, usize, f64);
+
+impl BlockSparseMatrix {
+    fn compress(
+        block_size: usize,
+        n_block_rows: usize,
+        n_block_cols: usize,
+        mut entries: Vec<BlockEntry>,
+    ) -> BlockSparseMatrix {
+        entries.sort_unstable_by_key(|(row, col, _position, _value)| (*row, *col));
+
+        let block_len = block_size * block_size;
+        let mut row_ptr = vec![0; n_block_rows + 1];
+        let mut block_cols: Vec<usize> = Vec::new();
+        let mut values: Vec<f64> = Vec::new();
+        let mut current: Option<(usize, usize)> = None;
+
+        for (row, col, position, value) in entries {
+            if current != Some((row, col)) {
+                current = Some((row, col));
+                block_cols.push(col);
+                values.resize(values.len() + block_len, 0.0);
+                row_ptr[row + 1] += 1;
+            }
+            let start = values.len() - block_len;
+            values[start + position] += value;
+        }
+
+        for i in 0..n_block_rows {
+            row_ptr[i + 1] += row_ptr[i];
+        }
+
+        BlockSparseMatrix {
+            block_size,
+            n_block_rows,
+            n_block_cols,
+            row_ptr,
+            block_cols,
+            values,
+        }
+    }
+
+    /// Builds the matrix from dense row-major blocks in any order, summing duplicates
+    // Direct assembly of the coupled system, for when make_system assembles one
+    #[allow(dead_code)]
+    pub fn from_blocks(
+        block_size: usize,
+        n_block_rows: usize,
+        n_block_cols: usize,
+        blocks: &[(usize, usize, Vec<f64>)],
+    ) -> Result<BlockSparseMatrix, String> {
+        let mut entries = Vec::with_capacity(blocks.len() * block_size * block_size);
+
+        for (row, col, block) in blocks.iter() {
+            if *row >= n_block_rows || *col >= n_block_cols {
+                return Err(format!(
+                    "Block ({}, {}) out of bounds for a {}x{} block matrix",
+                    row, col, n_block_rows, n_block_cols
+                ));
+            }
+            if block.len() != block_size * block_size {
+                return Err(format!(
+                    "Block ({}, {}) has {} values instead of {}",
+                    row,
+                    col,
+                    block.len(),
+                    block_size * block_size
+                ));
+            }
+            entries.extend(
+                block
+                    .iter()
+                    .enumerate()
+                    .map(|(position, value)| (*row, *col, position, *value)),
+            );
+        }
+
+        Ok(BlockSparseMatrix::compress(
+            block_size,
+            n_block_rows,
+            n_block_cols,
+            entries,
+        ))
+    }
+
+    /// Coupled operator from scalar operators between variables: entry (i, j) of the matrix
+    /// given for (row variable, col variable) lands in block (i, j) at that position. Gives
+    /// the coupled u, v, w, p system from the matrices of the segregated equations.
+    // The segregated matrices it couples are not assembled yet
+    #[allow(dead_code)]
+    pub fn from_couplings(
+        block_size: usize,
+        n_cells: usize,
+        couplings: &[(usize, usize, &SparseMatrix)],
+    ) -> Result<BlockSparseMatrix, String> {
+        let mut entries = Vec::new();
+
+        for (row_variable, col_variable, matrix) in couplings.iter() {
+            if *row_variable >= block_size || *col_variable >= block_size {
+                return Err(format!(
+                    "Coupling ({}, {}) out of bounds for blocks of size {}",
+                    row_variable, col_variable, block_size
+                ));
+            }
+            if matrix.n_rows != n_cells || matrix.n_cols != n_cells {
+                return Err(format!(
+                    "Coupling ({}, {}) is {}x{}, expected {}x{}",
+                    row_variable, col_variable, matrix.n_rows, matrix.n_cols, n_cells, n_cells
+                ));
+            }
+
+            let position = row_variable * block_size + col_variable;
+            entries.extend(
+                matrix
+                    .entries
+                    .iter()
+                    .map(|(row, col, value)| (*row, *col, position, *value)),
+            );
+        }
+
+        Ok(BlockSparseMatrix::compress(
+            block_size, n_cells, n_cells, entries,
+        ))
+    }
+
+    // Only the tests count the stored blocks
+    #[allow(dead_code)]
+    pub fn n_blocks(&self) -> usize {
+        self.block_cols.len()
+    }
+
+    pub fn block(&self, row: usize, col: usize) -> Option<&[f64]> {
+        let block_len = self.block_size * self.block_size;
+        let cols = &self.block_cols[self.row_ptr[row]..self.row_ptr[row + 1]];
+        cols.binary_search(&col).ok().map(|pos| {
+            let start = (self.row_ptr[row] + pos) * block_len;
+            &self.values[start..start + block_len]
+        })
+    }
+
+    /// One block per block row, zero where the diagonal block is missing
+    pub fn diagonal_blocks(&self) -> Vec<Vec<f64>> {
+        let block_len = self.block_size * self.block_size;
+        (0..self.n_block_rows)
+            .map(|row| {
+                self.block(row, row)
+                    .map_or_else(|| vec![0.0; block_len], |block| block.to_vec())
+            })
+            .collect()
+    }
+
+    pub fn dot(&self, x: &[f64]) -> Result<Vec<f64>, String> {
+        let bs = self.block_size;
+        if x.len() != self.n_block_cols * bs {
+            return Err(format!(
+                "Cannot multiply a {}x{} matrix with a {}x1 vector",
+                self.n_block_rows * bs,
+                self.n_block_cols * bs,
+                x.len()
+            ));
+        }
+
+        let mut y = vec![0.0; self.n_block_rows * bs];
+        y.par_chunks_mut(bs).enumerate().for_each(|(row, y_block)| {
+            for k in self.row_ptr[row]..self.row_ptr[row + 1] {
+                let block = &self.values[k * bs * bs..(k + 1) * bs * bs];
+                let x_block = &x[self.block_cols[k] * bs..(self.block_cols[k] + 1) * bs];
+                for (yi, block_row) in y_block.iter_mut().zip(block.chunks(bs)) {
+                    *yi += block_row
+                        .iter()
+                        .zip(x_block.iter())
+                        .map(|(a, b)| a * b)
+                        .sum::<f64>();
+                }
+            }
+        });
+
+        Ok(y)
+    }
+}
+
+impl MatrixRows for BlockSparseMatrix {
+    fn n_rows(&self) -> usize {
+        self.n_block_rows * self.block_size
+    }
+
+    fn n_cols(&self) -> usize {
+        self.n_block_cols * self.block_size
+    }
+
+    fn row(&self, row: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let bs = self.block_size;
+        let (block_row, local) = (row / bs, row % bs);
+        (self.row_ptr[block_row]..self.row_ptr[block_row + 1]).flat_map(move |k| {
+            (0..bs).map(move |c| {
+                (
+                    self.block_cols[k] * bs + c,
+                    self.values[k * bs * bs + local * bs + c],
+                )
+            })
+        })
+    }
+
+    fn dot(&self, x: &[f64]) -> Result<Vec<f64>, String> {
+        BlockSparseMatrix::dot(self, x)
+    }
+}
+
+/// Merges one field per variable into the cell-major numbering of a block matrix
+// Gathers the cell fields for coupled_solve, which has no caller yet
+#[allow(dead_code)]
+pub fn interleave_fields(fields: &[&[f64]]) -> Vec<f64> {
+    let n_cells = fields.first().map_or(0, |field| field.len());
+    (0..n_cells)
+        .flat_map(|cell| fields.iter().map(move |field| field[cell]))
+        .collect()
+}
+
+/// Inverse of `interleave_fields`
+// Scatters the result of coupled_solve back to the cell fields
+#[allow(dead_code)]
+pub fn split_fields(x: &[f64], block_size: usize) -> Vec<Vec<f64>> {
+    (0..block_size)
+        .map(|variable| {
+            x.iter()
+                .skip(variable)
+                .step_by(block_size)
+                .copied()
+                .collect()
+        })
+        .collect()
+}
+
+/// Inverse of a dense row-major block by Gauss-Jordan elimination with partial pivoting
+pub fn invert_block(block: &[f64], block_size: usize) -> Option<Vec<f64>> {
+    let n = block_size;
+    let mut a = block.to_vec();
+    let mut inverse: Vec<f64> = (0..n * n)
+        .map(|k| if k / n == k % n { 1.0 } else { 0.0 })
+        .collect();
+
+    for col in 0..n {
+        let pivot =
+            (col..n).max_by(|i, j| a[i * n + col].abs().total_cmp(&a[j * n + col].abs()))?;
+        if a[pivot * n + col] == 0.0 {
+            return None;
+        }
+        for k in 0..n {
+            a.swap(col * n + k, pivot * n + k);
+            inverse.swap(col * n + k, pivot * n + k);
+        }
+
+        let diagonal = a[col * n + col];
+        for k in 0..n {
+            a[col * n + k] /= diagonal;
+            inverse[col * n + k] /= diagonal;
+        }
+
+        for row in (0..n).filter(|row| *row != col) {
+            let factor = a[row * n + col];
+            if factor != 0.0 {
+                for k in 0..n {
+                    a[row * n + k] -= factor * a[col * n + k];
+                    inverse[row * n + k] -= factor * inverse[col * n + k];
+                }
+            }
+        }
+    }
+
+    Some(inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_system::sparse_system::SparseSystem;
+    use crate::sparse_system::test_matrices::tridiagonal;
+
+    #[test]
+    fn test_coupled_system() {
+        // Two variables per cell with a skew coupling between them
+        let n = 30;
+        let a_uu = tridiagonal(n, -1.0, 3.0, -1.0);
+        let a_pp = tridiagonal(n, -1.0, 2.5, -1.0);
+        let a_up = tridiagonal(n, 0.1, 0.5, 0.1);
+        let mut a_pu = a_up.clone();
+        a_pu.scale(-1.0);
+        let couplings = [(0, 0, &a_uu), (0, 1, &a_up), (1, 0, &a_pu), (1, 1, &a_pp)];
+        let a = BlockSparseMatrix::from_couplings(2, n, &couplings).unwrap();
+        assert_eq!(a.n_blocks(), 3 * n - 2);
+        assert_eq!(a.block(0, 0).unwrap(), &[3.0, 0.5, -0.5, 2.5]);
+        assert!(a.block(0, 5).is_none());
+
+        // Same operator with scalar storage in the interleaved numbering
+        let scalar_entries = couplings
+            .iter()
+            .flat_map(|(row_variable, col_variable, matrix)| {
+                matrix.entries.iter().map(move |(row, col, value)| {
+                    (2 * row + row_variable, 2 * col + col_variable, *value)
+                })
+            })
+            .collect();
+        let scalar = SparseMatrix::from_entries(scalar_entries, 2 * n, 2 * n);
+        let x = scalar.random_vec_like();
+        for (block, reference) in a.dot(&x).unwrap().iter().zip(scalar.dot(&x).unwrap()) {
+            assert!((block - reference).abs() < 1e-12);
+        }
+
+        let u = vec![1.0; n];
+        let p: Vec<f64> = (0..n).map(|i| i as f64).collect();
+        let b = interleave_fields(&[&u, &p]);
+        assert_eq!(split_fields(&b, 2), vec![u, p]);
+
+        let system = SparseSystem::new(&a, &b);
+        let result = system.coupled_solve(&vec![0.0; 2 * n], 30, 1e-20, 200);
+        assert!(result.converged, "{}", result.message);
+    }
+
+    #[test]
+    fn test_invert_block() {
+        let block = vec![0.0, 2.0, 1.0, 1.0, 0.0, 3.0, 4.0, 1.0, 0.0];
+        let inverse = invert_block(&block, 3).unwrap();
+        for i in 0..3 {
+            for j in 0..3 {
+                let product: f64 = (0..3).map(|k| block[i * 3 + k] * inverse[k * 3 + j]).sum();
+                assert!((product - if i == j { 1.0 } else { 0.0 }).abs() < 1e-12);
+            }
+        }
+        assert!(invert_block(&[1.0, 2.0, 2.0, 4.0], 2).is_none());
+    }
+}
@@ -0,0 +1,228 @@
+use crate::sparse_system::kernels;
+use crate::sparse_system::sparse_matrix::{MatrixRows, SparseMatrix};
+
+/// Compressed sparse row storage: the columns and values of row `i` are
+/// `col_indices[row_ptr[i]..row_ptr[i + 1]]` and `values[row_ptr[i]..row_ptr[i + 1]]`,
+/// sorted by column and without duplicates.
+#[derive(Clone, Debug)]
+pub struct CsrMatrix {
+    pub n_rows: usize,
+    pub n_cols: usize,
+    pub row_ptr: Vec<usize>,
+    pub col_indices: Vec<usize>,
+    pub values: Vec<f64>,
+}
+
+impl CsrMatrix {
+    /// Builds the matrix from (row, col, value) triplets in any order, summing duplicates
+    pub fn from_triplets(
+        n_rows: usize,
+        n_cols: usize,
+        triplets: &[(usize, usize, f64)],
+    ) -> Result<CsrMatrix, String> {
+        if let Some((row, col, _value)) = triplets
+            .iter()
+            .find(|(row, col, _value)| *row >= n_rows || *col >= n_cols)
+        {
+            return Err(format!(
+                "Entry ({}, {}) out of bounds for a {}x{} matrix",
+                row, col, n_rows, n_cols
+            ));
+        }
+
+        // Counting sort by row, then sort and merge every row by column
+        let mut row_ptr = vec![0; n_rows + 1];
+        for (row, _col, _value) in triplets.iter() {
+            row_ptr[row + 1] += 1;
+        }
+        for i in 0..n_rows {
+            row_ptr[i + 1] += row_ptr[i];
+        }
+
+        let mut next = row_ptr.clone();
+        let mut unsorted = vec![(0, 0.0); triplets.len()];
+        for (row, col, value) in triplets.iter() {
+            unsorted[next[*row]] = (*col, *value);
+            next[*row] += 1;
+        }
+
+        let mut compressed_ptr = vec![0; n_rows + 1];
+        let mut col_indices = Vec::with_capacity(triplets.len());
+        let mut values = Vec::with_capacity(triplets.len());
+
+        for row in 0..n_rows {
+            let entries = &mut unsorted[row_ptr[row]..row_ptr[row + 1]];
+            entries.sort_unstable_by_key(|(col, _value)| *col);
+
+            let start = col_indices.len();
+            for (col, value) in entries.iter() {
+                if col_indices.len() > start && col_indices.last() == Some(col) {
+                    *values.last_mut().unwrap() += value;
+                } else {
+                    col_indices.push(*col);
+                    values.push(*value);
+                }
+            }
+            compressed_ptr[row + 1] = col_indices.len();
+        }
+
+        Ok(CsrMatrix {
+            n_rows,
+            n_cols,
+            row_ptr: compressed_ptr,
+            col_indices,
+            values,
+        })
+    }
+
+    /// Compressed copy of any row-accessible matrix
+    pub fn from_rows(matrix: &impl MatrixRows) -> CsrMatrix {
+        let triplets: Vec<(usize, usize, f64)> = (0..matrix.n_rows())
+            .flat_map(|row| matrix.row(row).map(move |(col, value)| (row, col, value)))
+            .collect();
+
+        CsrMatrix::from_triplets(matrix.n_rows(), matrix.n_cols(), &triplets).unwrap()
+    }
+
+    pub fn to_sparse_matrix(&self) -> SparseMatrix {
+        let entries = (0..self.n_rows)
+            .flat_map(|row| self.row(row).map(move |(col, value)| (row, col, value)))
+            .collect();
+
+        SparseMatrix::from_entries(entries, self.n_rows, self.n_cols)
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn row_cols(&self, row: usize) -> &[usize] {
+        &self.col_indices[self.row_ptr[row]..self.row_ptr[row + 1]]
+    }
+
+    pub fn row_values(&self, row: usize) -> &[f64] {
+        &self.values[self.row_ptr[row]..self.row_ptr[row + 1]]
+    }
+
+    /// Position of entry (row, col) in `values`, if it is stored
+    pub fn find(&self, row: usize, col: usize) -> Option<usize> {
+        self.row_cols(row)
+            .binary_search(&col)
+            .ok()
+            .map(|pos| self.row_ptr[row] + pos)
+    }
+
+    /// The transpose in CSR form, which is also the CSC form of this matrix
+    pub fn transpose(&self) -> CsrMatrix {
+        let mut row_ptr = vec![0; self.n_cols + 1];
+        for col in self.col_indices.iter() {
+            row_ptr[col + 1] += 1;
+        }
+        for i in 0..self.n_cols {
+            row_ptr[i + 1] += row_ptr[i];
+        }
+
+        let mut next = row_ptr.clone();
+        let mut col_indices = vec![0; self.nnz()];
+        let mut values = vec![0.0; self.nnz()];
+        for row in 0..self.n_rows {
+            for (col, value) in self.row(row) {
+                col_indices[next[col]] = row;
+                values[next[col]] = value;
+                next[col] += 1;
+            }
+        }
+
+        CsrMatrix {
+            n_rows: self.n_cols,
+            n_cols: self.n_rows,
+            row_ptr,
+            col_indices,
+            values,
+        }
+    }
+
+    pub fn dot_par(&self, x: &[f64]) -> Result<Vec<f64>, String> {
+        if self.n_cols != x.len() {
+            return Err(format!(
+                "Cannot multiply a {}x{} matrix with a {}x1 vector",
+                self.n_rows,
+                self.n_cols,
+                x.len()
+            ));
+        }
+
+        let mut b = vec![0.0; self.n_rows];
+        kernels::spmv(self, x, &mut b);
+        Ok(b)
+    }
+}
+
+impl MatrixRows for CsrMatrix {
+    fn n_rows(&self) -> usize {
+        self.n_rows
+    }
+
+    fn n_cols(&self) -> usize {
+        self.n_cols
+    }
+
+    fn row(&self, row: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        self.row_cols(row)
+            .iter()
+            .copied()
+            .zip(self.row_values(row).iter().copied())
+    }
+
+    fn dot(&self, x: &[f64]) -> Result<Vec<f64>, String> {
+        self.dot_par(x)
+    }
+}
+
+impl From<&SparseMatrix> for CsrMatrix {
+    fn from(matrix: &SparseMatrix) -> CsrMatrix {
+        CsrMatrix::from_triplets(matrix.n_rows, matrix.n_cols, &matrix.entries).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_triplets_sums_duplicates() {
+        let triplets = vec![
+            (1, 0, 2.0),
+            (0, 1, 1.0),
+            (1, 0, 3.0),
+            (0, 0, 4.0),
+            (2, 2, 1.0),
+        ];
+        let csr = CsrMatrix::from_triplets(3, 3, &triplets).unwrap();
+        assert_eq!(csr.row_ptr, vec![0, 2, 3, 4]);
+        assert_eq!(csr.col_indices, vec![0, 1, 0, 2]);
+        assert_eq!(csr.values, vec![4.0, 1.0, 5.0, 1.0]);
+        assert!(CsrMatrix::from_triplets(2, 2, &triplets).is_err());
+
+        let x = vec![1.0, 2.0, 3.0];
+        assert_eq!(csr.dot(&x).unwrap(), vec![6.0, 5.0, 3.0]);
+        assert_eq!(csr.transpose().dot(&x).unwrap(), vec![14.0, 1.0, 3.0]);
+    }
+
+    #[test]
+    fn test_round_trip_with_triplets() {
+        let a = SparseMatrix::random(200, 1000, true);
+        let csr = CsrMatrix::from(&a);
+        let x = a.random_vec_like();
+        let expected = a.dot(&x).unwrap();
+        let back = csr.to_sparse_matrix();
+        for ((e, c), b) in expected
+            .iter()
+            .zip(csr.dot(&x).unwrap().iter())
+            .zip(back.dot(&x).unwrap().iter())
+        {
+            assert!((e - c).abs() < 1e-9);
+            assert!((e - b).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::sparse_system::preconditioner::Preconditioner;
+use crate::sparse_system::reordering::{self, Permutation};
+use crate::sparse_system::sparse_matrix::MatrixRows;
+
+/// LU factorization with partial pivoting of a banded matrix. The rows and columns are first
+/// renumbered with reverse Cuthill-McKee, so the band stays narrow for mesh matrices and
+/// the fill-in is limited to it. Row pivoting widens the upper band by the lower bandwidth.
+pub struct SparseLu {
+    n: usize,
+    lower_bandwidth: usize,
+    /// Number of stored entries per row of U, diagonal included
+    width: usize,
+    /// Row i of U holds columns i..i + width
+    upper: Vec<f64>,
+    /// Column k holds the multipliers of rows k + 1..=k + lower_bandwidth
+    multipliers: Vec<f64>,
+    pivots: Vec<usize>,
+    permutation: Permutation,
+}
+
+impl SparseLu {
+    pub fn new(matrix: &impl MatrixRows) -> Result<SparseLu, String> {
+        let n = matrix.n_rows();
+        if n != matrix.n_cols() {
+            return Err(format!(
+                "LU factorization needs a square matrix, got {}x{}",
+                n,
+                matrix.n_cols()
+            ));
+        }
+
+        let permutation = reordering::reverse_cuthill_mckee(&reordering::adjacency(matrix)?);
+
+        let mut lower_bandwidth = 0;
+        let mut upper_bandwidth = 0;
+        for row in 0..n {
+            let new_row = permutation.new_index(row);
+            for (col, _value) in matrix.row(row) {
+                let new_col = permutation.new_index(col);
+                lower_bandwidth = lower_bandwidth.max(new_row.saturating_sub(new_col));
+                upper_bandwidth = upper_bandwidth.max(new_col.saturating_sub(new_row));
+            }
+        }
+
+        // Working storage covers columns i - kl..=i + kl + ku of every row i
+        let kl = lower_bandwidth;
+        let row_width = 2 * kl + upper_bandwidth + 1;
+        let position = |row: usize, col: usize| row * row_width + col + kl - row;
+        let mut band = vec![0.0; n * row_width];
+        for row in 0..n {
+            let new_row = permutation.new_index(row);
+            for (col, value) in matrix.row(row) {
+                band[position(new_row, permutation.new_index(col))] += value;
+            }
+        }
+
+        // Pivots below round-off of the largest entry mean the matrix is numerically singular
+        let threshold = n as f64 * f64::EPSILON * band.iter().fold(0.0, |m: f64, v| m.max(v.abs()));
+        let width = kl + upper_bandwidth + 1;
+        let mut multipliers = vec![0.0; n * kl];
+        let mut pivots = vec![0; n];
+
+        for k in 0..n {
+            let last_row = (k + kl).min(n - 1);
+            let last_col = (k + width - 1).min(n - 1);
+
+            let pivot = (k..=last_row)
+                .max_by(|a, b| {
+                    band[position(*a, k)]
+                        .abs()
+                        .total_cmp(&band[position(*b, k)].abs())
+                })
+                .unwrap();
+            if band[position(pivot, k)].abs() <= threshold {
+                return Err(format!("Matrix is singular at column {}", k));
+            }
+
+            pivots[k] = pivot;
+            if pivot != k {
+                for col in k..=last_col {
+                    band.swap(position(k, col), position(pivot, col));
+                }
+            }
+
+            let diagonal = band[position(k, k)];
+            for row in k + 1..=last_row {
+                let multiplier = band[position(row, k)] / diagonal;
+                multipliers[k * kl + row - k - 1] = multiplier;
+                if multiplier != 0.0 {
+                    for col in k + 1..=last_col {
+                        band[position(row, col)] -= multiplier * band[position(k, col)];
+                    }
+                }
+            }
+        }
+
+        let upper = (0..n)
+            .flat_map(|row| (row..row + width).map(move |col| (row, col)))
+            .map(|(row, col)| {
+                if col < n {
+                    band[position(row, col)]
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        Ok(SparseLu {
+            n,
+            lower_bandwidth: kl,
+            width,
+            upper,
+            multipliers,
+            pivots,
+            permutation,
+        })
+    }
+
+    /// Solves A x = b with the stored factors
+    pub fn solve(&self, b: &[f64]) -> Result<Vec<f64>, String> {
+        if b.len() != self.n {
+            return Err(format!(
+                "Cannot solve a {}x{} system with a right hand side of {} values",
+                self.n,
+                self.n,
+                b.len()
+            ));
+        }
+
+        let kl = self.lower_bandwidth;
+        let mut y = self.permutation.apply(b);
+
+        // Forward elimination with the row swaps in the order they were made
+        for k in 0..self.n {
+            y.swap(k, self.pivots[k]);
+            let last_row = (k + kl).min(self.n - 1);
+            for row in k + 1..=last_row {
+                y[row] -= self.multipliers[k * kl + row - k - 1] * y[k];
+            }
+        }
+
+        for row in (0..self.n).rev() {
+            let u = &self.upper[row * self.width..(row + 1) * self.width];
+            let last_col = (row + self.width - 1).min(self.n - 1);
+            let sum: f64 = (row + 1..=last_col).map(|col| u[col - row] * y[col]).sum();
+            y[row] = (y[row] - sum) / u[0];
+        }
+
+        Ok(self.permutation.apply_inverse(&y))
+    }
+}
+
+impl Preconditioner for SparseLu {
+    fn apply(&self, r: &[f64], z: &mut [f64]) {
+        z.copy_from_slice(&self.solve(r).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_system::sparse_matrix::SparseMatrix;
+
+    #[test]
+    fn test_lu_needs_pivoting() {
+        // Zero leading diagonal entry, only solvable with row exchanges
+        let rows = vec![0, 0, 1, 1, 1, 2, 2];
+        let cols = vec![1, 2, 0, 1, 2, 0, 2];
+        let values = vec![2.0, 1.0, 3.0, 1.0, -1.0, 1.0, 4.0];
+        let a = SparseMatrix::from_vecs(&rows, &cols, &values);
+        let x = vec![1.0, -2.0, 0.5];
+        let b = a.dot(&x).unwrap();
+
+        let lu = SparseLu::new(&a).unwrap();
+        for (xi, ei) in lu.solve(&b).unwrap().iter().zip(x.iter()) {
+            assert!((xi - ei).abs() < 1e-12);
+        }
+
+        let singular = SparseMatrix::from_vecs(
+            &vec![0, 0, 1, 1],
+            &vec![0, 1, 0, 1],
+            &vec![1.0, 2.0, 2.0, 4.0],
+        );
+        assert!(SparseLu::new(&singular).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::sparse_system::sparse_matrix::MatrixRows;
+use rayon::prelude::*;
+use std::ops::{Add, Mul, Sub};
+
+/// Contiguous rows (or vector entries) handled by one rayon task. Large enough to amortise
+/// the scheduling and keep each task on its own cache lines.
+pub const CHUNK_SIZE: usize = 1024;
+
+/// y = A x, with the rows split in contiguous blocks across threads
+pub fn spmv(matrix: &impl MatrixRows, x: &[f64], y: &mut [f64]) {
+    y.par_chunks_mut(CHUNK_SIZE)
+        .enumerate()
+        .for_each(|(chunk, y_chunk)| {
+            let first_row = chunk * CHUNK_SIZE;
+            for (offset, yi) in y_chunk.iter_mut().enumerate() {
+                *yi = matrix
+                    .row(first_row + offset)
+                    .map(|(col, value)| value * x[col])
+                    .sum();
+            }
+        });
+}
+
+/// Storage type of the Krylov vectors. Coefficients and reductions stay in double precision
+/// whatever the storage.
+pub trait Scalar:
+    Copy + Default + Send + Sync + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self>
+{
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+impl Scalar for f64 {
+    fn from_f64(value: f64) -> f64 {
+        value
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl Scalar for f32 {
+    fn from_f64(value: f64) -> f32 {
+        value as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+/// Accumulated in double precision, which keeps single precision Krylov coefficients accurate
+/// at no extra memory traffic
+pub fn dot<T: Scalar>(a: &[T], b: &[T]) -> f64 {
+    a.par_chunks(CHUNK_SIZE)
+        .zip(b.par_chunks(CHUNK_SIZE))
+        .map(|(a_chunk, b_chunk)| {
+            a_chunk
+                .iter()
+                .zip(b_chunk.iter())
+                .map(|(ai, bi)| ai.to_f64() * bi.to_f64())
+                .sum::<f64>()
+        })
+        .sum()
+}
+
+pub fn norm_sq(a: &[f64]) -> f64 {
+    dot(a, a)
+}
+
+/// y += alpha x
+pub fn axpy<T: Scalar>(alpha: f64, x: &[T], y: &mut [T]) {
+    let alpha = T::from_f64(alpha);
+    y.par_chunks_mut(CHUNK_SIZE)
+        .zip(x.par_chunks(CHUNK_SIZE))
+        .for_each(|(y_chunk, x_chunk)| {
+            y_chunk
+                .iter_mut()
+                .zip(x_chunk.iter())
+                .for_each(|(yi, xi)| *yi = *yi + alpha * *xi);
+        });
+}
+
+/// y = x + beta y
+pub fn xpby(x: &[f64], beta: f64, y: &mut [f64]) {
+    y.par_chunks_mut(CHUNK_SIZE)
+        .zip(x.par_chunks(CHUNK_SIZE))
+        .for_each(|(y_chunk, x_chunk)| {
+            y_chunk
+                .iter_mut()
+                .zip(x_chunk.iter())
+                .for_each(|(yi, xi)| *yi = xi + beta * *yi);
+        });
+}
+
+/// ||b - A x||^2 without storing the residual
+pub fn residual_norm_sq(matrix: &impl MatrixRows, x: &[f64], b: &[f64]) -> f64 {
+    b.par_chunks(CHUNK_SIZE)
+        .enumerate()
+        .map(|(chunk, b_chunk)| {
+            let first_row = chunk * CHUNK_SIZE;
+            b_chunk
+                .iter()
+                .enumerate()
+                .map(|(offset, bi)| {
+                    let ax: f64 = matrix
+                        .row(first_row + offset)
+                        .map(|(col, value)| value * x[col])
+                        .sum();
+                    (bi - ax).powi(2)
+                })
+                .sum::<f64>()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_system::sparse_matrix::SparseMatrix;
+
+    #[test]
+    fn test_kernels_match_serial() {
+        let a = SparseMatrix::random(5000, 40000, true);
+        let x = a.random_vec_like();
+        let b = a.random_vec_like();
+
+        let mut y = vec![0.0; a.n_rows];
+        spmv(&a, &x, &mut y);
+        let serial = a.dot(&x).unwrap();
+        for (yi, si) in y.iter().zip(serial.iter()) {
+            assert!((yi - si).abs() < 1e-9 * si.abs().max(1.0));
+        }
+
+        let expected: f64 = x.iter().zip(b.iter()).map(|(xi, bi)| xi * bi).sum();
+        assert!((dot(&x, &b) - expected).abs() < 1e-9);
+
+        let residual: f64 = serial
+            .iter()
+            .zip(b.iter())
+            .map(|(ax, bi)| (bi - ax).powi(2))
+            .sum();
+        let fused = residual_norm_sq(&a, &x, &b);
+        assert!((fused - residual).abs() < 1e-9 * residual);
+
+        let mut z = b.clone();
+        axpy(2.0, &x, &mut z);
+        xpby(&x, 0.5, &mut z);
+        for ((zi, xi), bi) in z.iter().zip(x.iter()).zip(b.iter()) {
+            assert!((zi - (xi + 0.5 * (bi + 2.0 * xi))).abs() < 1e-12);
+        }
+        assert!((norm_sq(&x) - dot(&x, &x)).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::sparse_system::linear_solver::{LinearSolverSettings, Tolerance};
+use crate::sparse_system::preconditioner::Preconditioner;
+use crate::sparse_system::sparse_matrix::MatrixRows;
+use crate::sparse_system::sparse_system::{SolverResult, SparseSystem};
+use std::time::Instant;
+
+/// Anything that can compute y = A x. This is all the Krylov solvers need, so the matrix
+/// does not have to be stored.
+pub trait LinearOperator: Sync {
+    /// (rows, cols)
+    fn dimensions(&self) -> (usize, usize);
+    fn apply(&self, x: &[f64]) -> Result<Vec<f64>, String>;
+}
+
+impl<M: MatrixRows> LinearOperator for M {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.n_rows(), self.n_cols())
+    }
+
+    fn apply(&self, x: &[f64]) -> Result<Vec<f64>, String> {
+        self.dot(x)
+    }
+}
+
+/// Operator defined by a function computing A x
+pub struct MatrixFreeOperator<F> {
+    n_rows: usize,
+    n_cols: usize,
+    product: F,
+}
+
+/// Jacobian of a nonlinear residual F at a state u, applied with a first order finite
+/// difference: J v ~ (F(u + h v) - F(u)) / h
+pub struct FiniteDifferenceJacobian<F> {
+    residual: F,
+    state: Vec<f64>,
+    base: Vec<f64>,
+}
+
+// Stencil products without an assembled matrix, only the tests build one so far
+#[allow(dead_code)]
+impl<F: Fn(&[f64]) -> Vec<f64> + Sync> MatrixFreeOperator<F> {
+    pub fn new(n_rows: usize, n_cols: usize, product: F) -> MatrixFreeOperator<F> {
+        MatrixFreeOperator {
+            n_rows,
+            n_cols,
+            product,
+        }
+    }
+}
+
+impl<F: Fn(&[f64]) -> Vec<f64> + Sync> LinearOperator for MatrixFreeOperator<F> {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.n_rows, self.n_cols)
+    }
+
+    fn apply(&self, x: &[f64]) -> Result<Vec<f64>, String> {
+        if x.len() != self.n_cols {
+            return Err(format!(
+                "Cannot apply a {}x{} operator to a {}x1 vector",
+                self.n_rows,
+                self.n_cols,
+                x.len()
+            ));
+        }
+        Ok((self.product)(x))
+    }
+}
+
+fn norm(x: &[f64]) -> f64 {
+    x.iter().map(|xi| xi * xi).sum::<f64>().sqrt()
+}
+
+impl<F: Fn(&[f64]) -> Vec<f64> + Sync> FiniteDifferenceJacobian<F> {
+    pub fn new(residual: F, state: Vec<f64>) -> FiniteDifferenceJacobian<F> {
+        let base = residual(&state);
+        FiniteDifferenceJacobian {
+            residual,
+            state,
+            base,
+        }
+    }
+
+    /// F(u), evaluated once when the Jacobian is built
+    pub fn base_residual(&self) -> &[f64] {
+        &self.base
+    }
+}
+
+impl<F: Fn(&[f64]) -> Vec<f64> + Sync> LinearOperator for FiniteDifferenceJacobian<F> {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.base.len(), self.state.len())
+    }
+
+    fn apply(&self, v: &[f64]) -> Result<Vec<f64>, String> {
+        if v.len() != self.state.len() {
+            return Err(format!(
+                "Cannot apply a {}x{} Jacobian to a {}x1 vector",
+                self.base.len(),
+                self.state.len(),
+                v.len()
+            ));
+        }
+
+        let v_norm = norm(v);
+        if v_norm == 0.0 {
+            return Ok(vec![0.0; self.base.len()]);
+        }
+
+        // Step balancing truncation and round-off errors (Knoll & Keyes)
+        let h = f64::EPSILON.sqrt() * (1.0 + norm(&self.state)) / v_norm;
+        let perturbed: Vec<f64> = self
+            .state
+            .iter()
+            .zip(v.iter())
+            .map(|(ui, vi)| ui + h * vi)
+            .collect();
+
+        Ok((self.residual)(&perturbed)
+            .iter()
+            .zip(self.base.iter())
+            .map(|(fi, f0)| (fi - f0) / h)
+            .collect())
+    }
+}
+
+/// Jacobian-free Newton-Krylov for F(u) = 0: every Newton correction J du = -F(u) is
+/// solved by GMRES with finite difference Jacobian products. The tolerance, restart and
+/// iteration limit of the corrections come from `linear`, a relative tolerance being the
+/// forcing term of the Newton step. Its method and preconditioner kind are not used, the
+/// Jacobian is never assembled. `tol` bounds the squared norm of F, like the linear solvers.
+/// When a correction is not solved to its tolerance the iterations stop at the last state.
+// For the implicit compressible solver, which does not exist yet
+#[allow(dead_code)]
+pub fn newton_krylov_solve(
+    residual: impl Fn(&[f64]) -> Vec<f64> + Sync,
+    u0: &[f64],
+    preconditioner: &dyn Preconditioner,
+    linear: &LinearSolverSettings,
+    tol: f64,
+    max_iters: usize,
+) -> SolverResult {
+    let start = Instant::now();
+    let mut u = u0.to_vec();
+    let mut history = Vec::new();
+    let mut iters = 0;
+    let mut failure = None;
+
+    loop {
+        let jacobian = FiniteDifferenceJacobian::new(&residual, u.clone());
+        let error: f64 = jacobian.base_residual().iter().map(|fi| fi * fi).sum();
+        history.push(error);
+        if error < tol || iters == max_iters {
+            break;
+        }
+
+        let rhs: Vec<f64> = jacobian.base_residual().iter().map(|fi| -fi).collect();
+        let system = SparseSystem::new(&jacobian, &rhs);
+        let linear_tol = match linear.tolerance {
+            Tolerance::Absolute(tol) => tol * tol,
+            Tolerance::Relative(forcing) => forcing * forcing * error,
+        };
+        let correction = system.gmres_solve(
+            &vec![0.0; u.len()],
+            preconditioner,
+            linear.restart,
+            linear_tol,
+            linear.max_iters,
+        );
+
+        match correction.solution {
+            Some(du) if correction.converged => {
+                u.iter_mut().zip(du.iter()).for_each(|(ui, dui)| *ui += dui);
+                iters += 1;
+            }
+            _ => {
+                failure = Some(correction.message);
+                break;
+            }
+        }
+    }
+
+    let error = *history.last().unwrap();
+    let converged = error < tol;
+    let message = match failure {
+        None if converged => format!("Newton converged in {} iterations", iters),
+        None => format!(
+            "Newton not converged after {} iterations (error {:e} >= tol {:e})",
+            iters, error, tol
+        ),
+        Some(ref linear) => format!(
+            "Newton stopped after {} iterations, correction not solved: {}",
+            iters, linear
+        ),
+    };
+    SolverResult {
+        solution: Some(u),
+        converged,
+        diagonal_dominance: None,
+        iters,
+        tol,
+        max_iters_reached: !converged && failure.is_none(),
+        error: Some(error),
+        message,
+        elapsed_time: Some(start.elapsed()),
+        residual_history: history,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_system::linear_solver::{PreconditionerKind, SolverMethod};
+    use crate::sparse_system::preconditioner::IdentityPreconditioner;
+    use crate::sparse_system::test_matrices::laplacian_1d;
+
+    fn gmres() -> LinearSolverSettings {
+        let mut settings =
+            LinearSolverSettings::new(SolverMethod::Gmres, PreconditionerKind::Identity);
+        settings.tolerance = Tolerance::Relative(1e-4);
+        settings
+    }
+
+    #[test]
+    fn test_matrix_free_operator_matches_matrix() {
+        // Tridiagonal [-1 2 -1] stencil without storing the matrix
+        let n = 50;
+        let stencil = MatrixFreeOperator::new(n, n, |x: &[f64]| {
+            (0..x.len())
+                .map(|i| {
+                    let left = if i > 0 { x[i - 1] } else { 0.0 };
+                    let right = if i + 1 < x.len() { x[i + 1] } else { 0.0 };
+                    2.0 * x[i] - left - right
+                })
+                .collect()
+        });
+        let matrix = laplacian_1d(n);
+
+        let b = vec![1.0; n];
+        let matrix_free = SparseSystem::new(&stencil, &b);
+        let assembled = SparseSystem::new(&matrix, &b);
+        let x0 = vec![0.0; n];
+        let free_result =
+            matrix_free.conjugate_gradient_solve(&x0, &IdentityPreconditioner, 1e-20, 200);
+        let result = assembled.conjugate_gradient_solve(&x0, &IdentityPreconditioner, 1e-20, 200);
+        assert!(free_result.converged, "{}", free_result.message);
+        assert_eq!(free_result.iters, result.iters);
+        assert!(stencil.apply(&[1.0]).is_err());
+    }
+
+    #[test]
+    fn test_jacobian_free_newton_krylov() {
+        // u_i^3 + 4 u_i - u_{i-1} - u_{i+1} = 1 on a chain, solved from zero
+        let residual = |u: &[f64]| -> Vec<f64> {
+            (0..u.len())
+                .map(|i| {
+                    let left = if i > 0 { u[i - 1] } else { 0.0 };
+                    let right = if i + 1 < u.len() { u[i + 1] } else { 0.0 };
+                    u[i].powi(3) + 4.0 * u[i] - left - right - 1.0
+                })
+                .collect()
+        };
+
+        let result = newton_krylov_solve(
+            residual,
+            &vec![0.0; 40],
+            &IdentityPreconditioner,
+            &gmres(),
+            1e-20,
+            20,
+        );
+        assert!(result.converged, "{}", result.message);
+        assert!(result.iters < 10);
+        let errors = &result.residual_history;
+        assert!(errors.windows(2).all(|pair| pair[1] < pair[0]));
+    }
+
+    #[test]
+    fn test_newton_krylov_unsolvable_correction() {
+        // The second equation cannot be satisfied, no correction reduces it
+        let residual = |u: &[f64]| vec![u[0] + u[1] - 1.0, 1.0];
+        let result = newton_krylov_solve(
+            residual,
+            &[0.0, 0.0],
+            &IdentityPreconditioner,
+            &gmres(),
+            1e-20,
+            20,
+        );
+        assert!(!result.converged);
+        assert!(!result.max_iters_reached);
+        assert_eq!(result.iters, 0);
+        assert_eq!(result.solution, Some(vec![0.0, 0.0]));
+        assert_eq!(result.residual_history, vec![2.0]);
+        assert!(result.message.contains("correction"), "{}", result.message);
+    }
+}
@@ -0,0 +1,583 @@
+use crate::sparse_system::csr_matrix::CsrMatrix;
+use crate::sparse_system::multigrid::{AmgParameters, Multigrid};
+use crate::sparse_system::preconditioner::{
+    Ic0Preconditioner, IdentityPreconditioner, Ilu0Preconditioner, JacobiPreconditioner,
+    Preconditioner,
+};
+use crate::sparse_system::reordering::Colouring;
+use crate::sparse_system::sparse_matrix::{MatrixRows, SparseMatrix};
+use crate::sparse_system::sparse_system::{SolverResult, SparseSystem};
+use std::sync::{Arc, Mutex};
+
+/// Stopping criterion on the residual norm ||b - A x||
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tolerance {
+    Absolute(f64),
+    /// Relative to the residual of the initial guess
+    Relative(f64),
+}
+
+#[derive(Clone, Debug)]
+pub struct SolverConfig {
+    pub tolerance: Tolerance,
+    pub max_iters: usize,
+    /// Zero when not given. Unused by the direct solver.
+    pub initial_guess: Option<Vec<f64>>,
+}
+
+/// Common interface of the linear solvers, so the method used for each equation can be
+/// chosen at run time
+pub trait LinearSolver<M: MatrixRows = SparseMatrix>: Sync {
+    // Tells which solver the factory built, only the tests ask so far
+    #[allow(dead_code)]
+    fn name(&self) -> &'static str;
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreconditionerKind {
+    Identity,
+    Jacobi,
+    Ilu0,
+    Ic0,
+    Multigrid,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SolverMethod {
+    Jacobi,
+    GaussSeidel,
+    Sor,
+    Ssor,
+    ConjugateGradient,
+    BiCgStab,
+    Gmres,
+    Multigrid,
+    Direct,
+    MixedPrecision,
+}
+
+/// Everything needed to build and run the solver of one equation
+#[derive(Clone, Debug)]
+pub struct LinearSolverSettings {
+    pub method: SolverMethod,
+    pub preconditioner: PreconditionerKind,
+    pub tolerance: Tolerance,
+    pub max_iters: usize,
+    /// Krylov subspace size of GMRES
+    pub restart: usize,
+    /// Relaxation factor of SOR and SSOR
+    pub omega: f64,
+}
+
+pub struct JacobiSolver;
+
+pub struct GaussSeidelSolver {
+    colouring: ColouringCache,
+}
+
+pub struct SorSolver {
+    pub omega: f64,
+    colouring: ColouringCache,
+}
+
+pub struct SsorSolver {
+    pub omega: f64,
+    colouring: ColouringCache,
+}
+
+/// Colouring of the last matrix relaxed, rebuilt only when a matrix does not fit it
+#[derive(Default)]
+struct ColouringCache(Mutex<Option<Arc<Colouring>>>);
+
+pub struct ConjugateGradientSolver {
+    pub preconditioner: PreconditionerKind,
+}
+
+pub struct BiCgStabSolver {
+    pub preconditioner: PreconditionerKind,
+}
+
+pub struct GmresSolver {
+    pub restart: usize,
+    pub preconditioner: PreconditionerKind,
+}
+
+pub struct MultigridSolver {
+    pub params: AmgParameters,
+}
+
+/// Sparse LU factorisation, ignores the initial guess of the configuration
+pub struct DirectSolver;
+
+/// Single precision inner solves with double precision refinement, see
+/// `SparseSystem::mixed_precision_solve`
+pub struct MixedPrecisionSolver {
+    /// Residual reduction asked from every single precision solve
+    pub inner_reduction: f64,
+}
+
+impl SolverConfig {
+    pub fn new(tolerance: Tolerance, max_iters: usize) -> SolverConfig {
+        SolverConfig {
+            tolerance,
+            max_iters,
+            initial_guess: None,
+        }
+    }
+
+    pub fn initial_guess(&self, n: usize) -> Vec<f64> {
+        self.initial_guess.clone().unwrap_or_else(|| vec![0.0; n])
+    }
+
+    /// Bound on the squared residual, the measure the solvers compare against
+    pub fn squared_tolerance<M: MatrixRows>(&self, system: &SparseSystem<M>, x0: &[f64]) -> f64 {
+        match self.tolerance {
+            Tolerance::Absolute(tol) => tol * tol,
+            // Kept positive so an exact initial guess is accepted
+            Tolerance::Relative(tol) => (tol * tol * system.error_sq(x0)).max(f64::MIN_POSITIVE),
+        }
+    }
+}
+
+impl ColouringCache {
+    fn get(&self, matrix: &impl MatrixRows) -> Result<Arc<Colouring>, String> {
+        let mut cached = self.0.lock().unwrap();
+        match cached.as_ref() {
+            Some(colouring) if colouring.fits(matrix) => Ok(colouring.clone()),
+            _ => {
+                let colouring = Arc::new(Colouring::new(matrix)?);
+                *cached = Some(colouring.clone());
+                Ok(colouring)
+            }
+        }
+    }
+
+    /// SOR, or SSOR when `symmetric`, along the cached colouring
+    fn solve<M: MatrixRows>(
+        &self,
+        system: &SparseSystem<M>,
+        x0: &[f64],
+        omega: f64,
+        symmetric: bool,
+        tol: f64,
+        max_iters: usize,
+    ) -> SolverResult {
+        match self.get(system.matrix()) {
+            Ok(colouring) => {
+                system.multicolour_solve(x0, &colouring, omega, symmetric, tol, max_iters)
+            }
+            Err(message) => SolverResult::failure(tol, None, message),
+        }
+    }
+}
+
+impl GaussSeidelSolver {
+    pub fn new() -> GaussSeidelSolver {
+        GaussSeidelSolver {
+            colouring: ColouringCache::default(),
+        }
+    }
+}
+
+impl SorSolver {
+    pub fn new(omega: f64) -> SorSolver {
+        SorSolver {
+            omega,
+            colouring: ColouringCache::default(),
+        }
+    }
+}
+
+impl SsorSolver {
+    pub fn new(omega: f64) -> SsorSolver {
+        SsorSolver {
+            omega,
+            colouring: ColouringCache::default(),
+        }
+    }
+}
+
+impl PreconditionerKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PreconditionerKind::Identity => "none",
+            PreconditionerKind::Jacobi => "jacobi",
+            PreconditionerKind::Ilu0 => "ilu0",
+            PreconditionerKind::Ic0 => "ic0",
+            PreconditionerKind::Multigrid => "multigrid",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<PreconditionerKind, String> {
+        [
+            PreconditionerKind::Identity,
+            PreconditionerKind::Jacobi,
+            PreconditionerKind::Ilu0,
+            PreconditionerKind::Ic0,
+            PreconditionerKind::Multigrid,
+        ]
+        .into_iter()
+        .find(|kind| kind.name() == name)
+        .ok_or_else(|| format!("Unknown preconditioner '{}'", name))
+    }
+
+    pub fn build(&self, matrix: &impl MatrixRows) -> Result<Box<dyn Preconditioner>, String> {
+        Ok(match self {
+            PreconditionerKind::Identity => Box::new(IdentityPreconditioner),
+            PreconditionerKind::Jacobi => Box::new(JacobiPreconditioner::new(matrix)?),
+            PreconditionerKind::Ilu0 => Box::new(Ilu0Preconditioner::new(matrix)?),
+            PreconditionerKind::Ic0 => Box::new(Ic0Preconditioner::new(matrix)?),
+            PreconditionerKind::Multigrid => Box::new(Multigrid::smoothed_aggregation(
+                &CsrMatrix::from_rows(matrix).to_sparse_matrix(),
+                &AmgParameters::new(),
+            )?),
+        })
+    }
+}
+
+impl SolverMethod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SolverMethod::Jacobi => "jacobi",
+            SolverMethod::GaussSeidel => "gauss_seidel",
+            SolverMethod::Sor => "sor",
+            SolverMethod::Ssor => "ssor",
+            SolverMethod::ConjugateGradient => "cg",
+            SolverMethod::BiCgStab => "bicgstab",
+            SolverMethod::Gmres => "gmres",
+            SolverMethod::Multigrid => "multigrid",
+            SolverMethod::Direct => "direct",
+            SolverMethod::MixedPrecision => "mixed_precision",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<SolverMethod, String> {
+        [
+            SolverMethod::Jacobi,
+            SolverMethod::GaussSeidel,
+            SolverMethod::Sor,
+            SolverMethod::Ssor,
+            SolverMethod::ConjugateGradient,
+            SolverMethod::BiCgStab,
+            SolverMethod::Gmres,
+            SolverMethod::Multigrid,
+            SolverMethod::Direct,
+            SolverMethod::MixedPrecision,
+        ]
+        .into_iter()
+        .find(|method| method.name() == name)
+        .ok_or_else(|| format!("Unknown linear solver '{}'", name))
+    }
+}
+
+impl LinearSolverSettings {
+    pub fn new(method: SolverMethod, preconditioner: PreconditionerKind) -> LinearSolverSettings {
+        LinearSolverSettings {
+            method,
+            preconditioner,
+            tolerance: Tolerance::Relative(1e-6),
+            max_iters: 1000,
+            restart: 30,
+            omega: 1.5,
+        }
+    }
+
+    pub fn solver<M: MatrixRows>(&self) -> Box<dyn LinearSolver<M>> {
+        let preconditioner = self.preconditioner;
+        match self.method {
+            SolverMethod::Jacobi => Box::new(JacobiSolver),
+            SolverMethod::GaussSeidel => Box::new(GaussSeidelSolver::new()),
+            SolverMethod::Sor => Box::new(SorSolver::new(self.omega)),
+            SolverMethod::Ssor => Box::new(SsorSolver::new(self.omega)),
+            SolverMethod::ConjugateGradient => Box::new(ConjugateGradientSolver { preconditioner }),
+            SolverMethod::BiCgStab => Box::new(BiCgStabSolver { preconditioner }),
+            SolverMethod::Gmres => Box::new(GmresSolver {
+                restart: self.restart,
+                preconditioner,
+            }),
+            SolverMethod::Multigrid => Box::new(MultigridSolver {
+                params: AmgParameters::new(),
+            }),
+            SolverMethod::Direct => Box::new(DirectSolver),
+            SolverMethod::MixedPrecision => Box::new(MixedPrecisionSolver {
+                inner_reduction: 1e-3,
+            }),
+        }
+    }
+
+    pub fn config(&self) -> SolverConfig {
+        SolverConfig::new(self.tolerance, self.max_iters)
+    }
+}
+
+/// Runs `solve` with the initial guess and squared tolerance of `config`. The dimensions are
+/// checked first, a relative tolerance needs the residual of the initial guess.
+fn with_config<M: MatrixRows>(
+    system: &SparseSystem<M>,
+    config: &SolverConfig,
+    solve: impl FnOnce(&[f64], f64) -> SolverResult,
+) -> SolverResult {
+    let x0 = config.initial_guess(system.n_rows());
+    if let Err(message) = system.check_dimensions(&x0) {
+        let (Tolerance::Absolute(tol) | Tolerance::Relative(tol)) = config.tolerance;
+        return SolverResult::failure(tol * tol, None, message);
+    }
+
+    let tol = config.squared_tolerance(system, &x0);
+    solve(&x0, tol)
+}
+
+/// Same as `with_config`, with the preconditioner built for the system matrix
+fn with_preconditioner<M: MatrixRows>(
+    system: &SparseSystem<M>,
+    config: &SolverConfig,
+    kind: PreconditionerKind,
+    solve: impl FnOnce(&[f64], &dyn Preconditioner, f64) -> SolverResult,
+) -> SolverResult {
+    with_config(system, config, |x0, tol| {
+        match kind.build(system.matrix()) {
+            Ok(preconditioner) => solve(x0, preconditioner.as_ref(), tol),
+            Err(message) => SolverResult::failure(tol, None, message),
+        }
+    })
+}
+
+impl<M: MatrixRows> LinearSolver<M> for JacobiSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::Jacobi.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_config(system, config, |x0, tol| {
+            system.jacobi_solve(x0, tol, config.max_iters)
+        })
+    }
+}
+
+impl<M: MatrixRows> LinearSolver<M> for GaussSeidelSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::GaussSeidel.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_config(system, config, |x0, tol| {
+            self.colouring
+                .solve(system, x0, 1.0, false, tol, config.max_iters)
+        })
+    }
+}
+
+impl<M: MatrixRows> LinearSolver<M> for SorSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::Sor.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_config(system, config, |x0, tol| {
+            self.colouring
+                .solve(system, x0, self.omega, false, tol, config.max_iters)
+        })
+    }
+}
+
+impl<M: MatrixRows> LinearSolver<M> for SsorSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::Ssor.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_config(system, config, |x0, tol| {
+            self.colouring
+                .solve(system, x0, self.omega, true, tol, config.max_iters)
+        })
+    }
+}
+
+impl<M: MatrixRows> LinearSolver<M> for ConjugateGradientSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::ConjugateGradient.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_preconditioner(system, config, self.preconditioner, |x0, m, tol| {
+            system.conjugate_gradient_solve(x0, m, tol, config.max_iters)
+        })
+    }
+}
+
+impl<M: MatrixRows> LinearSolver<M> for BiCgStabSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::BiCgStab.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_preconditioner(system, config, self.preconditioner, |x0, m, tol| {
+            system.bicgstab_solve(x0, m, tol, config.max_iters)
+        })
+    }
+}
+
+impl<M: MatrixRows> LinearSolver<M> for GmresSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::Gmres.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_preconditioner(system, config, self.preconditioner, |x0, m, tol| {
+            system.gmres_solve(x0, m, self.restart, tol, config.max_iters)
+        })
+    }
+}
+
+impl<M: MatrixRows> LinearSolver<M> for MultigridSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::Multigrid.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_config(system, config, |x0, tol| {
+            let matrix = CsrMatrix::from_rows(system.matrix()).to_sparse_matrix();
+            match Multigrid::smoothed_aggregation(&matrix, &self.params) {
+                Ok(multigrid) => system.multigrid_solve(x0, &multigrid, tol, config.max_iters),
+                Err(message) => SolverResult::failure(tol, None, message),
+            }
+        })
+    }
+}
+
+impl<M: MatrixRows> LinearSolver<M> for DirectSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::Direct.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_config(system, config, |_x0, tol| system.direct_solve(tol))
+    }
+}
+
+impl<M: MatrixRows> LinearSolver<M> for MixedPrecisionSolver {
+    fn name(&self) -> &'static str {
+        SolverMethod::MixedPrecision.name()
+    }
+
+    fn solve(&self, system: &SparseSystem<M>, config: &SolverConfig) -> SolverResult {
+        with_config(system, config, |x0, tol| {
+            system.mixed_precision_solve(x0, self.inner_reduction, tol, config.max_iters)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_system::test_matrices::laplacian_1d;
+
+    #[test]
+    fn test_every_method_from_settings() {
+        let a = laplacian_1d(60);
+        let b = vec![1.0; 60];
+        let system = SparseSystem::new(&a, &b);
+
+        let methods = [
+            (SolverMethod::Jacobi, PreconditionerKind::Identity),
+            (SolverMethod::GaussSeidel, PreconditionerKind::Identity),
+            (SolverMethod::Sor, PreconditionerKind::Identity),
+            (SolverMethod::Ssor, PreconditionerKind::Identity),
+            (SolverMethod::ConjugateGradient, PreconditionerKind::Ic0),
+            (SolverMethod::BiCgStab, PreconditionerKind::Ilu0),
+            (SolverMethod::Gmres, PreconditionerKind::Jacobi),
+            (
+                SolverMethod::ConjugateGradient,
+                PreconditionerKind::Multigrid,
+            ),
+            (SolverMethod::Multigrid, PreconditionerKind::Identity),
+            (SolverMethod::Direct, PreconditionerKind::Identity),
+            (SolverMethod::MixedPrecision, PreconditionerKind::Identity),
+        ];
+
+        for (method, preconditioner) in methods {
+            let mut settings = LinearSolverSettings::new(method, preconditioner);
+            settings.max_iters = 50000;
+            let solver = settings.solver();
+            assert_eq!(SolverMethod::parse(solver.name()), Ok(method));
+
+            let result = solver.solve(&system, &settings.config());
+            assert!(result.converged, "{}: {}", solver.name(), result.message);
+            assert!(result.error.unwrap() < 1e-12 * system.error_sq(&vec![0.0; 60]));
+        }
+    }
+
+    #[test]
+    fn test_colouring_reused_across_systems() {
+        let a = laplacian_1d(30);
+        let mut scaled = a.clone();
+        scaled.scale(2.0);
+        let b = vec![1.0; 30];
+        let solver = SsorSolver::new(1.3);
+        let config = SolverConfig::new(Tolerance::Relative(1e-8), 5000);
+
+        assert!(solver.solve(&SparseSystem::new(&a, &b), &config).converged);
+        let first = solver.colouring.get(&a).unwrap();
+        assert!(
+            solver
+                .solve(&SparseSystem::new(&scaled, &b), &config)
+                .converged
+        );
+        assert!(Arc::ptr_eq(&first, &solver.colouring.get(&scaled).unwrap()));
+
+        let other = laplacian_1d(31);
+        let b = vec![1.0; 31];
+        assert!(
+            solver
+                .solve(&SparseSystem::new(&other, &b), &config)
+                .converged
+        );
+        assert!(!Arc::ptr_eq(&first, &solver.colouring.get(&other).unwrap()));
+    }
+
+    #[test]
+    fn test_absolute_tolerance_and_initial_guess() {
+        let a = laplacian_1d(20);
+        let b = vec![1.0; 20];
+        let system = SparseSystem::new(&a, &b);
+        let solver = ConjugateGradientSolver {
+            preconditioner: PreconditionerKind::Identity,
+        };
+
+        let mut config = SolverConfig::new(Tolerance::Absolute(1e-9), 100);
+        let result = solver.solve(&system, &config);
+        assert!(result.converged);
+        assert!(result.error.unwrap() < 1e-18);
+
+        config.initial_guess = result.solution;
+        let restarted = solver.solve(&system, &config);
+        assert!(restarted.converged);
+        assert_eq!(restarted.iters, 0);
+
+        let multigrid = MultigridSolver {
+            params: AmgParameters::new(),
+        };
+        let restarted = multigrid.solve(&system, &config);
+        assert!(restarted.converged, "{}", restarted.message);
+        assert_eq!(restarted.iters, 0);
+        assert_eq!(restarted.solution, config.initial_guess);
+        assert!(PreconditionerKind::parse("ilu1").is_err());
+    }
+
+    #[test]
+    fn test_wrong_initial_guess_length() {
+        let a = laplacian_1d(20);
+        let b = vec![1.0; 20];
+        let system = SparseSystem::new(&a, &b);
+        let mut config = SolverConfig::new(Tolerance::Relative(1e-8), 100);
+        config.initial_guess = Some(vec![0.0; 19]);
+
+        for method in [SolverMethod::Jacobi, SolverMethod::Sor, SolverMethod::Gmres] {
+            let settings = LinearSolverSettings::new(method, PreconditionerKind::Jacobi);
+            let result = settings.solver().solve(&system, &config);
+            assert!(result.solution.is_none());
+            assert!(result.message.starts_with("Wrong dimensions"));
+        }
+    }
+}
@@ -1,2 +1,14 @@
-pub mod sparse_matrix;
-pub mod sparse_system;
+pub mod block_matrix;
+pub mod csr_matrix;
+pub mod direct;
+pub mod kernels;
+pub mod linear_operator;
+pub mod linear_solver;
+pub mod mixed_precision;
+pub mod multigrid;
+pub mod preconditioner;
+pub mod reordering;
+pub mod sparse_matrix;
+pub mod sparse_system;
+#[cfg(test)]
+pub mod test_matrices;
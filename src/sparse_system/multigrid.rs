@@ -0,0 +1,340 @@
+use crate::sparse_system::direct::SparseLu;
+use crate::sparse_system::preconditioner::Preconditioner;
+use crate::sparse_system::sparse_matrix::SparseMatrix;
+
+pub struct AmgParameters {
+    /// j is strongly connected to i when |a_ij| >= threshold * sqrt(|a_ii * a_jj|)
+    pub strength_threshold: f64,
+    pub max_levels: usize,
+    /// Coarsening stops when a level has at most this many rows
+    pub coarse_size: usize,
+    /// Damping of the Jacobi step applied to the tentative prolongation, scaled by 1 / rho(D^-1 A)
+    pub prolongation_damping: f64,
+    pub pre_smoothing: usize,
+    pub post_smoothing: usize,
+    /// Coarsest levels up to this many rows are solved exactly with a sparse LU
+    pub max_direct_size: usize,
+    /// Symmetric Gauss-Seidel sweeps on coarsest levels too large or too singular for LU
+    pub coarse_sweeps: usize,
+}
+
+pub struct MultigridLevel {
+    pub matrix: SparseMatrix,
+    /// Maps corrections from the next coarser level onto this one
+    pub prolongation: Option<SparseMatrix>,
+    pub restriction: Option<SparseMatrix>,
+    diagonal: Vec<f64>,
+}
+
+pub struct Multigrid {
+    pub levels: Vec<MultigridLevel>,
+    pub pre_smoothing: usize,
+    pub post_smoothing: usize,
+    pub coarse_sweeps: usize,
+    coarse_solver: Option<SparseLu>,
+}
+
+impl AmgParameters {
+    pub fn new() -> AmgParameters {
+        AmgParameters {
+            strength_threshold: 0.08,
+            max_levels: 10,
+            coarse_size: 50,
+            prolongation_damping: 4.0 / 3.0,
+            pre_smoothing: 1,
+            post_smoothing: 1,
+            max_direct_size: 2000,
+            coarse_sweeps: 20,
+        }
+    }
+}
+
+fn diagonal_of(matrix: &SparseMatrix) -> Result<Vec<f64>, String> {
+    let diagonal = matrix.diagonal();
+
+    match diagonal.iter().position(|d| *d == 0.0) {
+        Some(row) => Err(format!("Zero or missing diagonal entry in row {}", row)),
+        None => Ok(diagonal),
+    }
+}
+
+/// Greedy aggregation over the strength-of-connection graph (Vanek, Mandel & Brezina)
+fn aggregate(matrix: &SparseMatrix, diagonal: &[f64], threshold: f64) -> (Vec<usize>, usize) {
+    let n = matrix.n_rows;
+    let strong: Vec<Vec<usize>> = (0..n)
+        .map(|i| {
+            matrix
+                .row_entries(i)
+                .iter()
+                .filter(|(_row, j, value)| {
+                    *j != i && value.abs() >= threshold * (diagonal[i] * diagonal[*j]).abs().sqrt()
+                })
+                .map(|(_row, j, _value)| *j)
+                .collect()
+        })
+        .collect();
+
+    let mut aggregates: Vec<Option<usize>> = vec![None; n];
+    let mut count = 0;
+
+    // Root nodes whose whole strong neighbourhood is still free
+    for i in 0..n {
+        if aggregates[i].is_none() && strong[i].iter().all(|j| aggregates[*j].is_none()) {
+            aggregates[i] = Some(count);
+            for j in strong[i].iter() {
+                aggregates[*j] = Some(count);
+            }
+            count += 1;
+        }
+    }
+
+    // Attach leftovers to a neighbouring aggregate
+    let first_pass = aggregates.clone();
+    for i in 0..n {
+        if aggregates[i].is_none() {
+            aggregates[i] = strong[i].iter().find_map(|j| first_pass[*j]);
+        }
+    }
+
+    // Whatever remains forms new aggregates with its free neighbours
+    for i in 0..n {
+        if aggregates[i].is_none() {
+            aggregates[i] = Some(count);
+            for j in strong[i].iter() {
+                if aggregates[*j].is_none() {
+                    aggregates[*j] = Some(count);
+                }
+            }
+            count += 1;
+        }
+    }
+
+    (aggregates.into_iter().map(|a| a.unwrap()).collect(), count)
+}
+
+/// Forward or backward Gauss-Seidel sweeps on A x = b
+fn smooth(
+    matrix: &SparseMatrix,
+    diagonal: &[f64],
+    b: &[f64],
+    x: &mut [f64],
+    sweeps: usize,
+    forward: bool,
+) {
+    let n = matrix.n_rows;
+    for _ in 0..sweeps {
+        for step in 0..n {
+            let row = if forward { step } else { n - 1 - step };
+            let off_diagonal_sum: f64 = matrix
+                .row_entries(row)
+                .iter()
+                .filter(|(_row, col, _value)| *col != row)
+                .map(|(_row, col, value)| value * x[*col])
+                .sum();
+            x[row] = (b[row] - off_diagonal_sum) / diagonal[row];
+        }
+    }
+}
+
+impl MultigridLevel {
+    fn new(matrix: SparseMatrix) -> Result<MultigridLevel, String> {
+        let diagonal = diagonal_of(&matrix)?;
+        Ok(MultigridLevel {
+            matrix,
+            prolongation: None,
+            restriction: None,
+            diagonal,
+        })
+    }
+}
+
+impl Multigrid {
+    /// Smoothed aggregation algebraic multigrid hierarchy with Galerkin coarse operators
+    pub fn smoothed_aggregation(
+        matrix: &SparseMatrix,
+        params: &AmgParameters,
+    ) -> Result<Multigrid, String> {
+        if matrix.n_rows != matrix.n_cols {
+            return Err(format!(
+                "Multigrid needs a square matrix, got {}x{}",
+                matrix.n_rows, matrix.n_cols
+            ));
+        }
+
+        let mut levels = vec![MultigridLevel::new(SparseMatrix::from_entries(
+            matrix.entries.clone(),
+            matrix.n_rows,
+            matrix.n_cols,
+        ))?];
+
+        while levels.len() < params.max_levels {
+            let level = levels.last().unwrap();
+            let a = &level.matrix;
+            if a.n_rows <= params.coarse_size {
+                break;
+            }
+
+            let (aggregates, n_coarse) = aggregate(a, &level.diagonal, params.strength_threshold);
+            if n_coarse == 0 || n_coarse >= a.n_rows {
+                break;
+            }
+
+            let tentative_entries = aggregates
+                .iter()
+                .enumerate()
+                .map(|(i, agg)| (i, *agg, 1.0))
+                .collect();
+            let tentative = SparseMatrix::from_entries(tentative_entries, a.n_rows, n_coarse);
+
+            // P = (I - omega D^-1 A) P_tent, with rho(D^-1 A) bounded by Gershgorin
+            let spectral_radius = (0..a.n_rows)
+                .map(|i| {
+                    a.row_entries(i)
+                        .iter()
+                        .map(|(_row, _col, value)| value.abs())
+                        .sum::<f64>()
+                        / level.diagonal[i].abs()
+                })
+                .fold(0.0, f64::max);
+            let omega = params.prolongation_damping / spectral_radius;
+
+            let ap = a.matmul(&tentative)?;
+            let mut entries: Vec<(usize, usize, f64)> = ap
+                .entries
+                .iter()
+                .map(|(row, col, value)| (*row, *col, -omega * value / level.diagonal[*row]))
+                .collect();
+            entries.extend(tentative.entries.iter().copied());
+            let prolongation = SparseMatrix::from_entries(entries, a.n_rows, n_coarse);
+            Multigrid::push_galerkin_level(&mut levels, prolongation)?;
+        }
+
+        Ok(Multigrid::new(levels, params))
+    }
+
+    /// Hierarchy from user supplied prolongations (finest first), e.g. geometric transfer
+    /// operators between nested meshes. Coarse operators are still Galerkin products.
+    pub fn from_prolongations(
+        matrix: &SparseMatrix,
+        prolongations: Vec<SparseMatrix>,
+        params: &AmgParameters,
+    ) -> Result<Multigrid, String> {
+        let mut levels = vec![MultigridLevel::new(SparseMatrix::from_entries(
+            matrix.entries.clone(),
+            matrix.n_rows,
+            matrix.n_cols,
+        ))?];
+
+        for prolongation in prolongations {
+            let n_fine = levels.last().unwrap().matrix.n_rows;
+            if prolongation.n_rows != n_fine {
+                return Err(format!(
+                    "Prolongation with {} rows does not match a level with {} rows",
+                    prolongation.n_rows, n_fine
+                ));
+            }
+            Multigrid::push_galerkin_level(&mut levels, prolongation)?;
+        }
+
+        Ok(Multigrid::new(levels, params))
+    }
+
+    fn new(levels: Vec<MultigridLevel>, params: &AmgParameters) -> Multigrid {
+        let coarsest = &levels.last().unwrap().matrix;
+        // A singular coarse operator (e.g. pure Neumann problems) falls back to sweeps
+        let coarse_solver = if coarsest.n_rows <= params.max_direct_size {
+            SparseLu::new(coarsest).ok()
+        } else {
+            None
+        };
+
+        Multigrid {
+            levels,
+            pre_smoothing: params.pre_smoothing,
+            post_smoothing: params.post_smoothing,
+            coarse_sweeps: params.coarse_sweeps,
+            coarse_solver,
+        }
+    }
+
+    /// A_c = P^T A P
+    fn push_galerkin_level(
+        levels: &mut Vec<MultigridLevel>,
+        prolongation: SparseMatrix,
+    ) -> Result<(), String> {
+        let level = levels.last_mut().unwrap();
+        let restriction = prolongation.transpose();
+        let coarse = restriction.matmul(&level.matrix.matmul(&prolongation)?)?;
+
+        level.prolongation = Some(prolongation);
+        level.restriction = Some(restriction);
+        levels.push(MultigridLevel::new(coarse)?);
+        Ok(())
+    }
+
+    pub fn n_rows(&self) -> usize {
+        self.levels[0].matrix.n_rows
+    }
+
+    /// One V-cycle on level `index`, improving `x` in place
+    pub fn v_cycle(&self, index: usize, b: &[f64], x: &mut [f64]) {
+        let level = &self.levels[index];
+
+        let (Some(prolongation), Some(restriction)) = (&level.prolongation, &level.restriction)
+        else {
+            if let Some(lu) = &self.coarse_solver {
+                x.copy_from_slice(&lu.solve(b).unwrap());
+                return;
+            }
+
+            // Symmetric sweeps keep the cycle symmetric
+            for _ in 0..self.coarse_sweeps {
+                smooth(&level.matrix, &level.diagonal, b, x, 1, true);
+                smooth(&level.matrix, &level.diagonal, b, x, 1, false);
+            }
+            return;
+        };
+
+        smooth(
+            &level.matrix,
+            &level.diagonal,
+            b,
+            x,
+            self.pre_smoothing,
+            true,
+        );
+
+        let residual: Vec<f64> = level
+            .matrix
+            .dot(x)
+            .unwrap()
+            .iter()
+            .zip(b.iter())
+            .map(|(ax, bi)| bi - ax)
+            .collect();
+        let coarse_b = restriction.dot(&residual).unwrap();
+        let mut coarse_x = vec![0.0; coarse_b.len()];
+        self.v_cycle(index + 1, &coarse_b, &mut coarse_x);
+
+        let correction = prolongation.dot(&coarse_x).unwrap();
+        x.iter_mut()
+            .zip(correction.iter())
+            .for_each(|(xi, ci)| *xi += ci);
+
+        smooth(
+            &level.matrix,
+            &level.diagonal,
+            b,
+            x,
+            self.post_smoothing,
+            false,
+        );
+    }
+}
+
+impl Preconditioner for Multigrid {
+    fn apply(&self, r: &[f64], z: &mut [f64]) {
+        z.fill(0.0);
+        self.v_cycle(0, r, z);
+    }
+}
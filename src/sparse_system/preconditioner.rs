@@ -0,0 +1,271 @@
+use crate::sparse_system::block_matrix::{self, BlockSparseMatrix};
+use crate::sparse_system::csr_matrix::CsrMatrix;
+use crate::sparse_system::sparse_matrix::MatrixRows;
+
+pub trait Preconditioner: Sync {
+    /// Approximately solves M z = r
+    fn apply(&self, r: &[f64], z: &mut [f64]);
+}
+
+pub struct IdentityPreconditioner;
+
+pub struct JacobiPreconditioner {
+    inverse_diagonal: Vec<f64>,
+}
+
+impl Preconditioner for IdentityPreconditioner {
+    fn apply(&self, r: &[f64], z: &mut [f64]) {
+        z.copy_from_slice(r);
+    }
+}
+
+impl JacobiPreconditioner {
+    pub fn new(matrix: &impl MatrixRows) -> Result<JacobiPreconditioner, String> {
+        let diagonal: Vec<f64> = (0..matrix.n_rows())
+            .map(|row| {
+                matrix
+                    .row(row)
+                    .filter(|(col, _value)| *col == row)
+                    .map(|(_col, value)| value)
+                    .sum()
+            })
+            .collect();
+
+        if let Some(row) = diagonal.iter().position(|d| *d == 0.0) {
+            return Err(format!("Zero or missing diagonal entry in row {}", row));
+        }
+
+        Ok(JacobiPreconditioner {
+            inverse_diagonal: diagonal.into_iter().map(|d| 1.0 / d).collect(),
+        })
+    }
+}
+
+impl Preconditioner for JacobiPreconditioner {
+    fn apply(&self, r: &[f64], z: &mut [f64]) {
+        for ((zi, ri), inv_d) in z.iter_mut().zip(r.iter()).zip(self.inverse_diagonal.iter()) {
+            *zi = ri * inv_d;
+        }
+    }
+}
+
+/// Inverts the diagonal blocks of a block matrix, so the variables of a cell stay coupled
+pub struct BlockJacobiPreconditioner {
+    block_size: usize,
+    inverse_blocks: Vec<Vec<f64>>,
+}
+
+impl BlockJacobiPreconditioner {
+    pub fn new(matrix: &BlockSparseMatrix) -> Result<BlockJacobiPreconditioner, String> {
+        let inverse_blocks = matrix
+            .diagonal_blocks()
+            .iter()
+            .enumerate()
+            .map(|(row, block)| {
+                block_matrix::invert_block(block, matrix.block_size)
+                    .ok_or_else(|| format!("Singular diagonal block in block row {}", row))
+            })
+            .collect::<Result<Vec<Vec<f64>>, String>>()?;
+
+        Ok(BlockJacobiPreconditioner {
+            block_size: matrix.block_size,
+            inverse_blocks,
+        })
+    }
+}
+
+impl Preconditioner for BlockJacobiPreconditioner {
+    fn apply(&self, r: &[f64], z: &mut [f64]) {
+        let bs = self.block_size;
+        for ((z_block, r_block), inverse) in z
+            .chunks_mut(bs)
+            .zip(r.chunks(bs))
+            .zip(self.inverse_blocks.iter())
+        {
+            for (zi, inverse_row) in z_block.iter_mut().zip(inverse.chunks(bs)) {
+                *zi = inverse_row
+                    .iter()
+                    .zip(r_block.iter())
+                    .map(|(a, b)| a * b)
+                    .sum();
+            }
+        }
+    }
+}
+
+/// Incomplete LU factorization with zero fill-in. L (unit diagonal) and U share the
+/// sparsity pattern of the original matrix.
+pub struct Ilu0Preconditioner {
+    factors: CsrMatrix,
+    diagonal_ptr: Vec<usize>,
+}
+
+impl Ilu0Preconditioner {
+    pub fn new(matrix: &impl MatrixRows) -> Result<Ilu0Preconditioner, String> {
+        let mut factors = CsrMatrix::from_rows(matrix);
+        let n = factors.n_rows;
+
+        let diagonal_ptr = (0..n)
+            .map(|row| {
+                factors
+                    .find(row, row)
+                    .ok_or_else(|| format!("Missing diagonal entry in row {}", row))
+            })
+            .collect::<Result<Vec<usize>, String>>()?;
+
+        for i in 0..n {
+            for kk in factors.row_ptr[i]..diagonal_ptr[i] {
+                let k = factors.col_indices[kk];
+                let pivot = factors.values[diagonal_ptr[k]];
+                if pivot == 0.0 {
+                    return Err(format!("Zero pivot in row {}", k));
+                }
+                factors.values[kk] /= pivot;
+
+                for jj in (kk + 1)..factors.row_ptr[i + 1] {
+                    if let Some(kj) = factors.find(k, factors.col_indices[jj]) {
+                        factors.values[jj] -= factors.values[kk] * factors.values[kj];
+                    }
+                }
+            }
+        }
+
+        if let Some(row) = (0..n).find(|&row| factors.values[diagonal_ptr[row]] == 0.0) {
+            return Err(format!("Zero pivot in row {}", row));
+        }
+
+        Ok(Ilu0Preconditioner {
+            factors,
+            diagonal_ptr,
+        })
+    }
+}
+
+impl Preconditioner for Ilu0Preconditioner {
+    fn apply(&self, r: &[f64], z: &mut [f64]) {
+        let CsrMatrix {
+            row_ptr,
+            col_indices,
+            values,
+            ..
+        } = &self.factors;
+
+        // L y = r
+        for i in 0..self.diagonal_ptr.len() {
+            let sum: f64 = (row_ptr[i]..self.diagonal_ptr[i])
+                .map(|jj| values[jj] * z[col_indices[jj]])
+                .sum();
+            z[i] = r[i] - sum;
+        }
+
+        // U z = y
+        for i in (0..self.diagonal_ptr.len()).rev() {
+            let sum: f64 = ((self.diagonal_ptr[i] + 1)..row_ptr[i + 1])
+                .map(|jj| values[jj] * z[col_indices[jj]])
+                .sum();
+            z[i] = (z[i] - sum) / values[self.diagonal_ptr[i]];
+        }
+    }
+}
+
+/// Incomplete Cholesky factorization with zero fill-in, A ~ L L^T, for symmetric
+/// positive-definite matrices. Only the lower triangle of the matrix is read.
+pub struct Ic0Preconditioner {
+    row_ptr: Vec<usize>,
+    cols: Vec<usize>,
+    values: Vec<f64>,
+}
+
+impl Ic0Preconditioner {
+    pub fn new(matrix: &impl MatrixRows) -> Result<Ic0Preconditioner, String> {
+        let CsrMatrix {
+            row_ptr: full_row_ptr,
+            col_indices: full_cols,
+            values: full_values,
+            ..
+        } = CsrMatrix::from_rows(matrix);
+        let n = matrix.n_rows();
+
+        // Keep the lower triangle, diagonal last in each row
+        let mut row_ptr = vec![0; n + 1];
+        let mut cols = Vec::with_capacity(full_cols.len() / 2 + n);
+        let mut values = Vec::with_capacity(full_cols.len() / 2 + n);
+        for i in 0..n {
+            for jj in full_row_ptr[i]..full_row_ptr[i + 1] {
+                if full_cols[jj] <= i {
+                    cols.push(full_cols[jj]);
+                    values.push(full_values[jj]);
+                }
+            }
+            row_ptr[i + 1] = cols.len();
+
+            if cols[row_ptr[i]..row_ptr[i + 1]].last() != Some(&i) {
+                return Err(format!("Missing diagonal entry in row {}", i));
+            }
+        }
+
+        for i in 0..n {
+            for ij in row_ptr[i]..row_ptr[i + 1] {
+                let j = cols[ij];
+
+                // Sparse dot product of rows i and j of L restricted to columns < j
+                let (mut a, mut b) = (row_ptr[i], row_ptr[j]);
+                let mut sum = 0.0;
+                while a < ij && b < row_ptr[j + 1] - 1 {
+                    match cols[a].cmp(&cols[b]) {
+                        std::cmp::Ordering::Less => a += 1,
+                        std::cmp::Ordering::Greater => b += 1,
+                        std::cmp::Ordering::Equal => {
+                            sum += values[a] * values[b];
+                            a += 1;
+                            b += 1;
+                        }
+                    }
+                }
+
+                let s = values[ij] - sum;
+                if j < i {
+                    values[ij] = s / values[row_ptr[j + 1] - 1];
+                } else if s <= 0.0 {
+                    return Err(format!(
+                        "Non-positive pivot {:e} in row {}, the matrix is not positive definite",
+                        s, i
+                    ));
+                } else {
+                    values[ij] = s.sqrt();
+                }
+            }
+        }
+
+        Ok(Ic0Preconditioner {
+            row_ptr,
+            cols,
+            values,
+        })
+    }
+}
+
+impl Preconditioner for Ic0Preconditioner {
+    fn apply(&self, r: &[f64], z: &mut [f64]) {
+        let n = self.row_ptr.len() - 1;
+
+        // L y = r
+        for i in 0..n {
+            let diagonal = self.row_ptr[i + 1] - 1;
+            let sum: f64 = (self.row_ptr[i]..diagonal)
+                .map(|jj| self.values[jj] * z[self.cols[jj]])
+                .sum();
+            z[i] = (r[i] - sum) / self.values[diagonal];
+        }
+
+        // L^T z = y, sweeping the rows of L backwards
+        for i in (0..n).rev() {
+            let diagonal = self.row_ptr[i + 1] - 1;
+            z[i] /= self.values[diagonal];
+            let zi = z[i];
+            for jj in self.row_ptr[i]..diagonal {
+                z[self.cols[jj]] -= self.values[jj] * zi;
+            }
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use crate::sparse_system::sparse_matrix::MatrixRows;
+use std::collections::VecDeque;
+
+/// Renumbering of n unknowns: new index `i` holds what was at `new_to_old[i]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Permutation {
+    new_to_old: Vec<usize>,
+    old_to_new: Vec<usize>,
+}
+
+impl Permutation {
+    pub fn from_new_to_old(new_to_old: Vec<usize>) -> Result<Permutation, String> {
+        let n = new_to_old.len();
+        let mut old_to_new = vec![usize::MAX; n];
+
+        for (new, old) in new_to_old.iter().enumerate() {
+            if *old >= n || old_to_new[*old] != usize::MAX {
+                return Err(format!("Index {} is out of range or repeated", old));
+            }
+            old_to_new[*old] = new;
+        }
+
+        Ok(Permutation {
+            new_to_old,
+            old_to_new,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.new_to_old.len()
+    }
+
+    // Only there because clippy asks for it next to len
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.new_to_old.is_empty()
+    }
+
+    pub fn new_index(&self, old: usize) -> usize {
+        self.old_to_new[old]
+    }
+
+    pub fn old_index(&self, new: usize) -> usize {
+        self.new_to_old[new]
+    }
+
+    /// Reorders a field given in the old numbering into the new one
+    pub fn apply<T: Clone>(&self, values: &[T]) -> Vec<T> {
+        self.new_to_old
+            .iter()
+            .map(|old| values[*old].clone())
+            .collect()
+    }
+
+    /// Reorders a field given in the new numbering back into the old one
+    pub fn apply_inverse<T: Clone>(&self, values: &[T]) -> Vec<T> {
+        self.old_to_new
+            .iter()
+            .map(|new| values[*new].clone())
+            .collect()
+    }
+}
+
+/// Neighbours of every row in the symmetrised pattern of a square matrix, without the diagonal
+pub fn adjacency(matrix: &impl MatrixRows) -> Result<Vec<Vec<usize>>, String> {
+    if matrix.n_rows() != matrix.n_cols() {
+        return Err(format!(
+            "Adjacency needs a square matrix, got {}x{}",
+            matrix.n_rows(),
+            matrix.n_cols()
+        ));
+    }
+
+    let mut adjacency = vec![Vec::new(); matrix.n_rows()];
+    for row in 0..matrix.n_rows() {
+        for (col, _value) in matrix.row(row).filter(|(col, _value)| *col != row) {
+            adjacency[row].push(col);
+            adjacency[col].push(row);
+        }
+    }
+    adjacency.iter_mut().for_each(|neighbours| {
+        neighbours.sort_unstable();
+        neighbours.dedup();
+    });
+    Ok(adjacency)
+}
+
+/// Greedy colouring: nodes of the same colour are never neighbours, so they can be updated
+/// in parallel by Gauss-Seidel. Returns the nodes of each colour in increasing order.
+pub fn greedy_colouring(adjacency: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut colour_of: Vec<Option<usize>> = vec![None; adjacency.len()];
+    let mut colours: Vec<Vec<usize>> = Vec::new();
+    let mut taken: Vec<bool> = Vec::new();
+
+    for node in 0..adjacency.len() {
+        taken.iter_mut().for_each(|t| *t = false);
+        for neighbour in adjacency[node].iter() {
+            if let Some(colour) = colour_of[*neighbour] {
+                taken[colour] = true;
+            }
+        }
+
+        let colour = taken.iter().position(|t| !t).unwrap_or(colours.len());
+        if colour == colours.len() {
+            colours.push(Vec::new());
+            taken.push(false);
+        }
+        colour_of[node] = Some(colour);
+        colours[colour].push(node);
+    }
+
+    colours
+}
+
+/// Greedy colouring of the rows of a matrix. Building it takes the adjacency of the whole
+/// pattern, so it is kept and reused by the multicolour sweeps of matrices that fit it.
+#[derive(Clone, Debug)]
+pub struct Colouring {
+    pub colours: Vec<Vec<usize>>,
+    colour_of: Vec<usize>,
+}
+
+impl Colouring {
+    pub fn new(matrix: &impl MatrixRows) -> Result<Colouring, String> {
+        let colours = greedy_colouring(&adjacency(matrix)?);
+        let mut colour_of = vec![0; matrix.n_rows()];
+        for (colour, rows) in colours.iter().enumerate() {
+            for row in rows.iter() {
+                colour_of[*row] = colour;
+            }
+        }
+        Ok(Colouring { colours, colour_of })
+    }
+
+    /// Whether the colouring covers `matrix` and no two rows of a colour are coupled in it,
+    /// which holds for any matrix with the pattern it was built from
+    pub fn fits(&self, matrix: &impl MatrixRows) -> bool {
+        matrix.n_rows() == self.colour_of.len()
+            && (0..matrix.n_rows()).all(|row| {
+                matrix.row(row).all(|(col, _value)| {
+                    col == row || self.colour_of.get(col) != Some(&self.colour_of[row])
+                })
+            })
+    }
+}
+
+/// Largest |i - j| over the edges of the graph
+// Measures what the renumbering gains, only the tests check it
+#[allow(dead_code)]
+pub fn bandwidth(adjacency: &[Vec<usize>]) -> usize {
+    adjacency
+        .iter()
+        .enumerate()
+        .flat_map(|(i, neighbours)| neighbours.iter().map(move |j| i.abs_diff(*j)))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Breadth first levels from `root` within its connected component
+fn level_structure(adjacency: &[Vec<usize>], root: usize) -> Vec<Vec<usize>> {
+    let mut visited = vec![false; adjacency.len()];
+    let mut levels = vec![vec![root]];
+    visited[root] = true;
+
+    loop {
+        let mut next = Vec::new();
+        for node in levels.last().unwrap().iter() {
+            for neighbour in adjacency[*node].iter() {
+                if !visited[*neighbour] {
+                    visited[*neighbour] = true;
+                    next.push(*neighbour);
+                }
+            }
+        }
+        if next.is_empty() {
+            return levels;
+        }
+        levels.push(next);
+    }
+}
+
+/// Node far from every other one in its component, found with the George & Liu iteration
+fn pseudo_peripheral_node(adjacency: &[Vec<usize>], start: usize) -> usize {
+    let mut root = start;
+    let mut levels = level_structure(adjacency, root);
+
+    loop {
+        let candidate = *levels
+            .last()
+            .unwrap()
+            .iter()
+            .min_by_key(|node| adjacency[**node].len())
+            .unwrap();
+        let candidate_levels = level_structure(adjacency, candidate);
+        if candidate_levels.len() <= levels.len() {
+            return root;
+        }
+        root = candidate;
+        levels = candidate_levels;
+    }
+}
+
+/// Reverse Cuthill-McKee ordering of an undirected graph given as adjacency lists. Every
+/// connected component is numbered from a pseudo-peripheral node, visiting neighbours by
+/// increasing degree, and the whole order is reversed at the end.
+pub fn reverse_cuthill_mckee(adjacency: &[Vec<usize>]) -> Permutation {
+    let n = adjacency.len();
+    let mut order = Vec::with_capacity(n);
+    let mut visited = vec![false; n];
+
+    let mut by_degree: Vec<usize> = (0..n).collect();
+    by_degree.sort_by_key(|node| adjacency[*node].len());
+
+    for start in by_degree {
+        if visited[start] {
+            continue;
+        }
+
+        let root = pseudo_peripheral_node(adjacency, start);
+        let mut queue = VecDeque::from([root]);
+        visited[root] = true;
+
+        while let Some(node) = queue.pop_front() {
+            order.push(node);
+
+            let mut neighbours: Vec<usize> = adjacency[node]
+                .iter()
+                .copied()
+                .filter(|neighbour| !visited[*neighbour])
+                .collect();
+            neighbours.sort_by_key(|neighbour| adjacency[*neighbour].len());
+            neighbours.dedup();
+
+            for neighbour in neighbours {
+                if !visited[neighbour] {
+                    visited[neighbour] = true;
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+
+    order.reverse();
+    Permutation::from_new_to_old(order).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permutation_round_trip() {
+        let permutation = Permutation::from_new_to_old(vec![2, 0, 1]).unwrap();
+        let field = vec!["a", "b", "c"];
+        assert_eq!(permutation.apply(&field), vec!["c", "a", "b"]);
+        assert_eq!(permutation.apply_inverse(&permutation.apply(&field)), field);
+        assert_eq!(permutation.new_index(2), 0);
+        assert!(Permutation::from_new_to_old(vec![0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_rcm_reduces_bandwidth() {
+        // 2D grid numbered with a stride that scatters neighbours, plus an isolated node
+        let (nx, ny) = (12, 7);
+        let n = nx * ny;
+        let scrambled = |i: usize| (i * 37) % n;
+        let mut adjacency = vec![Vec::new(); n + 1];
+        for i in 0..nx {
+            for j in 0..ny {
+                let node = scrambled(i * ny + j);
+                if i + 1 < nx {
+                    let other = scrambled((i + 1) * ny + j);
+                    adjacency[node].push(other);
+                    adjacency[other].push(node);
+                }
+                if j + 1 < ny {
+                    let other = scrambled(i * ny + j + 1);
+                    adjacency[node].push(other);
+                    adjacency[other].push(node);
+                }
+            }
+        }
+
+        let permutation = reverse_cuthill_mckee(&adjacency);
+        assert_eq!(permutation.len(), n + 1);
+
+        let renumbered: Vec<Vec<usize>> = permutation
+            .apply(&adjacency)
+            .into_iter()
+            .map(|neighbours| {
+                neighbours
+                    .iter()
+                    .map(|old| permutation.new_index(*old))
+                    .collect()
+            })
+            .collect();
+        assert!(bandwidth(&adjacency) > 2 * ny);
+        assert!(bandwidth(&renumbered) <= ny + 1);
+    }
+
+    #[test]
+    fn test_greedy_colouring() {
+        // 5-point stencil on a 6x5 grid takes two colours, like a chessboard
+        let (nx, ny) = (6, 5);
+        let mut adjacency = vec![Vec::new(); nx * ny];
+        for i in 0..nx {
+            for j in 0..ny {
+                if i + 1 < nx {
+                    adjacency[i * ny + j].push((i + 1) * ny + j);
+                    adjacency[(i + 1) * ny + j].push(i * ny + j);
+                }
+                if j + 1 < ny {
+                    adjacency[i * ny + j].push(i * ny + j + 1);
+                    adjacency[i * ny + j + 1].push(i * ny + j);
+                }
+            }
+        }
+
+        let colours = greedy_colouring(&adjacency);
+        assert_eq!(colours.len(), 2);
+        assert_eq!(colours.iter().map(|c| c.len()).sum::<usize>(), nx * ny);
+        for colour in colours.iter() {
+            for node in colour.iter() {
+                assert!(adjacency[*node].iter().all(|other| !colour.contains(other)));
+            }
+        }
+    }
+}
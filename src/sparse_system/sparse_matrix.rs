@@ -1,7 +1,8 @@
+use crate::sparse_system::csr_matrix::CsrMatrix;
+use crate::sparse_system::kernels;
+use crate::sparse_system::reordering::{self, Permutation};
 use itertools::izip;
 use rand::Rng;
-use rayon::iter::IntoParallelRefIterator;
-use rayon::iter::ParallelIterator;
 use std::fmt;
 use std::fs::File;
 use std::io::{BufRead, BufReader, BufWriter, Write};
@@ -9,6 +10,16 @@ use std::path::Path;
 
 type SparseEntry = (usize, usize, f64);
 
+/// Row-wise read access shared by the sparse storage formats, so the solvers can run on
+/// any of them
+pub trait MatrixRows: Sync {
+    fn n_rows(&self) -> usize;
+    fn n_cols(&self) -> usize;
+    /// (col, value) pairs of a row. Formats that allow duplicates may repeat a column.
+    fn row(&self, row: usize) -> impl Iterator<Item = (usize, f64)> + '_;
+    fn dot(&self, x: &[f64]) -> Result<Vec<f64>, String>;
+}
+
 #[derive(Clone)]
 pub struct SparseMatrix {
     pub entries: Vec<SparseEntry>,
@@ -17,6 +28,46 @@ pub struct SparseMatrix {
     pub row_indices: Vec<Option<(usize, usize)>>,
 }
 
+/// Incremental assembly from face loops: entries can be added several times and are
+/// summed when the matrix is built
+pub struct SparseMatrixBuilder {
+    entries: Vec<SparseEntry>,
+    n_rows: usize,
+    n_cols: usize,
+}
+
+// For the finite-volume assembly of Mesh::make_system, which is still a todo
+#[allow(dead_code)]
+impl SparseMatrixBuilder {
+    pub fn new(n_rows: usize, n_cols: usize) -> Self {
+        SparseMatrixBuilder::with_capacity(n_rows, n_cols, 0)
+    }
+
+    pub fn with_capacity(n_rows: usize, n_cols: usize, estimated_entries_count: usize) -> Self {
+        SparseMatrixBuilder {
+            entries: Vec::with_capacity(estimated_entries_count),
+            n_rows,
+            n_cols,
+        }
+    }
+
+    pub fn add(&mut self, row: usize, col: usize, value: f64) -> Result<(), String> {
+        if row >= self.n_rows || col >= self.n_cols {
+            return Err(format!(
+                "Entry ({}, {}) out of bounds for a {}x{} matrix",
+                row, col, self.n_rows, self.n_cols
+            ));
+        }
+
+        self.entries.push((row, col, value));
+        Ok(())
+    }
+
+    pub fn build(self) -> SparseMatrix {
+        SparseMatrix::from_entries(self.entries, self.n_rows, self.n_cols)
+    }
+}
+
 impl SparseMatrix {
     #[allow(dead_code)]
     pub fn new(estimated_entries_count: usize) -> Self {
@@ -50,22 +101,60 @@ impl SparseMatrix {
         // Assume entries are sorted
         self.row_indices = vec![None; self.n_rows];
 
-        let mut current_row = 0;
         let mut start_index = 0;
 
-        for (i, &(row, _, _)) in self.entries.iter().enumerate() {
-            if i == 0 {
-                continue;
-            }
+        for i in 1..=self.entries.len() {
+            let row = self.entries[start_index].0;
 
-            if current_row != row {
-                self.row_indices[current_row] = Some((start_index, i - 1));
-                current_row = row;
+            if i == self.entries.len() || self.entries[i].0 != row {
+                self.row_indices[row] = Some((start_index, i - 1));
                 start_index = i;
             }
         }
+    }
+
+    /// Merges repeated (row, col) entries into one. Assume entries are sorted
+    pub fn sum_duplicates(&mut self) {
+        let mut merged: Vec<SparseEntry> = Vec::with_capacity(self.entries.len());
 
-        self.row_indices[current_row] = Some((start_index, self.entries.len() - 1));
+        for &(row, col, value) in self.entries.iter() {
+            match merged.last_mut() {
+                Some(last) if last.0 == row && last.1 == col => last.2 += value,
+                _ => merged.push((row, col, value)),
+            }
+        }
+
+        self.entries = merged;
+        self.compute_row_indices();
+    }
+
+    /// Zeroes every value but keeps the sparsity pattern, to assemble again with `add_value`
+    // Reassembly on the pattern of a previous outer iteration, needs make_system too
+    #[allow(dead_code)]
+    pub fn reset_values(&mut self) {
+        self.entries.iter_mut().for_each(|entry| entry.2 = 0.0);
+    }
+
+    /// Adds to an entry of the existing sparsity pattern. Assume entries are sorted and
+    /// without duplicates, as left by `SparseMatrixBuilder::build`
+    // Pairs with reset_values
+    #[allow(dead_code)]
+    pub fn add_value(&mut self, row: usize, col: usize, value: f64) -> Result<(), String> {
+        let position = self
+            .row_indices
+            .get(row)
+            .copied()
+            .flatten()
+            .and_then(|(a, b)| {
+                self.entries[a..=b]
+                    .binary_search_by_key(&col, |(_row, col, _value)| *col)
+                    .ok()
+                    .map(|pos| a + pos)
+            })
+            .ok_or_else(|| format!("Entry ({}, {}) is not in the sparsity pattern", row, col))?;
+
+        self.entries[position].2 += value;
+        Ok(())
     }
 
     pub fn preprocess(&mut self) {
@@ -111,6 +200,20 @@ impl SparseMatrix {
             .collect()
     }
 
+    /// Matrix of the given shape, with entries sorted and duplicates summed
+    pub fn from_entries(entries: Vec<SparseEntry>, n_rows: usize, n_cols: usize) -> SparseMatrix {
+        let mut matrix = SparseMatrix {
+            entries,
+            n_rows,
+            n_cols,
+            row_indices: Vec::new(),
+        };
+
+        matrix.sort_entries();
+        matrix.sum_duplicates();
+        matrix
+    }
+
     pub fn from_vecs(rows: &Vec<usize>, cols: &Vec<usize>, values: &Vec<f64>) -> SparseMatrix {
         let entries = izip!(rows, cols, values)
             .map(|(row, col, val)| (*row, *col, *val))
@@ -137,19 +240,11 @@ impl SparseMatrix {
             ));
         }
 
-        let b = self
-            .row_indices
-            .par_iter()
-            .map(|maybe_range| match maybe_range {
-                Some((a, b)) => (*a..=*b)
-                    .map(|i| self.entries[i].2 * x[self.entries[i].1])
-                    .sum(),
-                None => 0.0,
-            })
-            .collect();
-
+        let mut b = vec![0.0; self.n_rows];
+        kernels::spmv(self, x, &mut b);
         Ok(b)
     }
+
     pub fn dot(&self, x: &[f64]) -> Result<Vec<f64>, String> {
         if self.n_cols != x.len() {
             return Err(format!(
@@ -176,11 +271,184 @@ impl SparseMatrix {
             .filter(|(row, col, _value)| *row == *col)
     }
 
+    /// One value per row, 0.0 where the diagonal entry is missing
     pub fn diagonal_values(&self) -> impl Iterator<Item = f64> + '_ {
-        self.entries
+        (0..self.n_rows.min(self.n_cols)).map(|row| self.diagonal_value(row).unwrap_or(0.0))
+    }
+
+    pub fn diagonal(&self) -> Vec<f64> {
+        self.diagonal_values().collect()
+    }
+
+    /// Looks the diagonal up within its row only. Repeated entries are summed.
+    pub fn diagonal_value(&self, row: usize) -> Option<f64> {
+        self.row_entries(row)
             .iter()
-            .filter(|(row, col, _value)| *row == *col)
+            .filter(|(_row, col, _value)| *col == row)
             .map(|(_row, _col, value)| *value)
+            .reduce(|a, b| a + b)
+    }
+
+    pub fn row_entries(&self, row: usize) -> &[SparseEntry] {
+        match self.row_indices.get(row).copied().flatten() {
+            Some((a, b)) => &self.entries[a..=b],
+            None => &[],
+        }
+    }
+
+    /// Scans every entry of the matrix. Repeated column access is cheaper on the rows of
+    /// `transpose()`.
+    // Inspection of single columns while debugging the assembly, no solver needs it
+    #[allow(dead_code)]
+    pub fn column_entries(&self, col: usize) -> Vec<SparseEntry> {
+        self.entries
+            .iter()
+            .filter(|(_row, c, _value)| *c == col)
+            .copied()
+            .collect()
+    }
+
+    pub fn transpose(&self) -> SparseMatrix {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(row, col, value)| (*col, *row, *value))
+            .collect();
+        SparseMatrix::from_entries(entries, self.n_cols, self.n_rows)
+    }
+
+    // With linear_combination, for the Schur complement of the coupled pressure-velocity
+    // system, which the coupled solver does not form yet
+    #[allow(dead_code)]
+    pub fn scale(&mut self, factor: f64) {
+        self.entries.iter_mut().for_each(|entry| entry.2 *= factor);
+    }
+
+    /// alpha * self + beta * other
+    // See scale
+    #[allow(dead_code)]
+    pub fn linear_combination(
+        &self,
+        alpha: f64,
+        other: &SparseMatrix,
+        beta: f64,
+    ) -> Result<SparseMatrix, String> {
+        if self.n_rows != other.n_rows || self.n_cols != other.n_cols {
+            return Err(format!(
+                "Cannot add a {}x{} matrix and a {}x{} matrix",
+                self.n_rows, self.n_cols, other.n_rows, other.n_cols
+            ));
+        }
+
+        let entries = self
+            .entries
+            .iter()
+            .map(|(row, col, value)| (*row, *col, alpha * value))
+            .chain(
+                other
+                    .entries
+                    .iter()
+                    .map(|(row, col, value)| (*row, *col, beta * value)),
+            )
+            .collect();
+
+        Ok(SparseMatrix::from_entries(
+            entries,
+            self.n_rows,
+            self.n_cols,
+        ))
+    }
+
+    /// Sparse matrix-matrix product, row by row with a dense accumulator (Gustavson)
+    pub fn matmul(&self, other: &SparseMatrix) -> Result<SparseMatrix, String> {
+        if self.n_cols != other.n_rows {
+            return Err(format!(
+                "Cannot multiply a {}x{} matrix with a {}x{} matrix",
+                self.n_rows, self.n_cols, other.n_rows, other.n_cols
+            ));
+        }
+
+        let mut entries = Vec::new();
+        let mut accumulator = vec![0.0; other.n_cols];
+        let mut touched: Vec<usize> = Vec::new();
+        let mut is_touched = vec![false; other.n_cols];
+
+        for row in 0..self.n_rows {
+            for (_row, k, a_value) in self.row_entries(row) {
+                for (_k, col, b_value) in other.row_entries(*k) {
+                    if !is_touched[*col] {
+                        is_touched[*col] = true;
+                        touched.push(*col);
+                    }
+                    accumulator[*col] += a_value * b_value;
+                }
+            }
+
+            touched.sort_unstable();
+            for col in touched.drain(..) {
+                entries.push((row, col, accumulator[col]));
+                accumulator[col] = 0.0;
+                is_touched[col] = false;
+            }
+        }
+
+        Ok(SparseMatrix::from_entries(
+            entries,
+            self.n_rows,
+            other.n_cols,
+        ))
+    }
+
+    // Same as reordering::bandwidth, for the tests of rcm_permutation
+    #[allow(dead_code)]
+    pub fn bandwidth(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(row, col, _value)| row.abs_diff(*col))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Reverse Cuthill-McKee ordering of the rows and columns of a square matrix
+    // Main renumbers the mesh before assembly, so no assembled system needs reordering yet
+    #[allow(dead_code)]
+    pub fn rcm_permutation(&self) -> Result<Permutation, String> {
+        Ok(reordering::reverse_cuthill_mckee(&reordering::adjacency(
+            self,
+        )?))
+    }
+
+    /// Symmetric permutation P A P^T, so that the system in the new numbering is solved by
+    /// `permutation.apply(x)` when the old one is solved by `x`
+    // Pairs with rcm_permutation
+    #[allow(dead_code)]
+    pub fn permute(&self, permutation: &Permutation) -> Result<SparseMatrix, String> {
+        if self.n_rows != self.n_cols || permutation.len() != self.n_rows {
+            return Err(format!(
+                "Cannot permute a {}x{} matrix with a permutation of {} indices",
+                self.n_rows,
+                self.n_cols,
+                permutation.len()
+            ));
+        }
+
+        let entries = self
+            .entries
+            .iter()
+            .map(|(row, col, value)| {
+                (
+                    permutation.new_index(*row),
+                    permutation.new_index(*col),
+                    *value,
+                )
+            })
+            .collect();
+
+        Ok(SparseMatrix::from_entries(
+            entries,
+            self.n_rows,
+            self.n_cols,
+        ))
     }
 
     pub fn off_diagonal_entries(&self) -> impl Iterator<Item = &SparseEntry> {
@@ -230,6 +498,156 @@ impl SparseMatrix {
         matrix.preprocess();
         Ok(matrix)
     }
+
+    /// Writes the Matrix Market coordinate format with 1-based indices. With `symmetric` only
+    /// the lower triangle is written, and the matrix must actually be symmetric.
+    // Debugging aid to compare assembled systems with other codes, no caller in the solver
+    #[allow(dead_code)]
+    pub fn save_matrix_market(
+        &self,
+        file_path: impl AsRef<Path>,
+        symmetric: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let csr = CsrMatrix::from(self);
+
+        if symmetric {
+            let transpose = csr.transpose();
+            if self.n_rows != self.n_cols
+                || csr.col_indices != transpose.col_indices
+                || csr.values != transpose.values
+            {
+                return Err("Cannot save a non symmetric matrix as symmetric".into());
+            }
+        }
+
+        let file = File::create(file_path)?;
+        let mut writer = BufWriter::new(file);
+
+        let entries: Vec<(usize, usize, f64)> = (0..csr.n_rows)
+            .flat_map(|row| csr.row(row).map(move |(col, value)| (row, col, value)))
+            .filter(|(row, col, _value)| !symmetric || row >= col)
+            .collect();
+
+        let symmetry = if symmetric { "symmetric" } else { "general" };
+        writeln!(writer, "%%MatrixMarket matrix coordinate real {}", symmetry)?;
+        writeln!(writer, "{} {} {}", self.n_rows, self.n_cols, entries.len())?;
+        for (row, col, val) in entries.iter() {
+            writeln!(writer, "{} {} {:e}", row + 1, col + 1, val)?;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Reads a Matrix Market coordinate file (real, integer or pattern; general, symmetric or
+    /// skew-symmetric). Duplicated entries are summed.
+    // Reads reference systems back, only the tests do for now
+    #[allow(dead_code)]
+    pub fn load_matrix_market(
+        file_path: impl AsRef<Path>,
+    ) -> Result<SparseMatrix, Box<dyn std::error::Error>> {
+        let file = File::open(file_path)?;
+        let mut lines = BufReader::new(file).lines();
+
+        let header = lines.next().ok_or("Empty Matrix Market file")??;
+        let banner: Vec<String> = header
+            .split_whitespace()
+            .map(|word| word.to_lowercase())
+            .collect();
+        if banner.len() != 5 || banner[0] != "%%matrixmarket" || banner[1] != "matrix" {
+            return Err(format!("Invalid Matrix Market header '{}'", header).into());
+        }
+        if banner[2] != "coordinate" {
+            return Err(format!("Unsupported Matrix Market format '{}'", banner[2]).into());
+        }
+
+        let field = banner[3].as_str();
+        if !matches!(field, "real" | "integer" | "pattern") {
+            return Err(format!("Unsupported Matrix Market field '{}'", field).into());
+        }
+
+        let mirror_sign = match banner[4].as_str() {
+            "general" => None,
+            "symmetric" => Some(1.0),
+            "skew-symmetric" => Some(-1.0),
+            other => return Err(format!("Unsupported Matrix Market symmetry '{}'", other).into()),
+        };
+
+        let mut data = lines.filter(|line| match line {
+            Ok(line) => !line.trim().is_empty() && !line.starts_with('%'),
+            Err(_) => true,
+        });
+
+        let size_line = data.next().ok_or("Missing Matrix Market size line")??;
+        let sizes: Vec<usize> = size_line
+            .split_whitespace()
+            .map(|part| part.parse())
+            .collect::<Result<_, _>>()?;
+        if sizes.len() != 3 {
+            return Err(format!("Invalid Matrix Market size line '{}'", size_line).into());
+        }
+        let (n_rows, n_cols, n_entries) = (sizes[0], sizes[1], sizes[2]);
+
+        let mut builder = SparseMatrixBuilder::with_capacity(n_rows, n_cols, n_entries);
+        let mut count = 0;
+
+        for line in data {
+            let line = line?;
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let expected = if field == "pattern" { 2 } else { 3 };
+            if parts.len() < expected {
+                return Err(format!("Invalid Matrix Market entry '{}'", line).into());
+            }
+
+            let row: usize = parts[0].parse()?;
+            let col: usize = parts[1].parse()?;
+            if row == 0 || col == 0 {
+                return Err(format!("Matrix Market indices are 1-based, got '{}'", line).into());
+            }
+            let val: f64 = if field == "pattern" {
+                1.0
+            } else {
+                parts[2].parse()?
+            };
+
+            builder.add(row - 1, col - 1, val)?;
+            if let Some(sign) = mirror_sign {
+                if row != col {
+                    builder.add(col - 1, row - 1, sign * val)?;
+                }
+            }
+            count += 1;
+        }
+
+        if count != n_entries {
+            return Err(format!("Expected {} entries, found {}", n_entries, count).into());
+        }
+
+        Ok(builder.build())
+    }
+}
+
+impl MatrixRows for SparseMatrix {
+    fn n_rows(&self) -> usize {
+        self.n_rows
+    }
+
+    fn n_cols(&self) -> usize {
+        self.n_cols
+    }
+
+    fn row(&self, row: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let entries = match self.row_indices[row] {
+            Some((a, b)) => &self.entries[a..=b],
+            None => &[],
+        };
+        entries.iter().map(|(_row, col, value)| (*col, *value))
+    }
+
+    fn dot(&self, x: &[f64]) -> Result<Vec<f64>, String> {
+        self.dot_par(x)
+    }
 }
 
 impl fmt::Display for SparseMatrix {
@@ -242,3 +660,52 @@ impl fmt::Display for SparseMatrix {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_indices_with_empty_rows() {
+        let mut matrix = SparseMatrix {
+            entries: vec![(1, 0, 2.0), (1, 2, 3.0), (3, 3, 4.0)],
+            n_rows: 5,
+            n_cols: 4,
+            row_indices: Vec::new(),
+        };
+        matrix.compute_row_indices();
+        assert_eq!(
+            matrix.row_indices,
+            vec![None, Some((0, 1)), None, Some((2, 2)), None]
+        );
+
+        let mut empty = SparseMatrix::new(0);
+        empty.n_rows = 3;
+        empty.compute_row_indices();
+        assert_eq!(empty.row_indices, vec![None; 3]);
+    }
+
+    #[test]
+    fn test_matrix_algebra() {
+        // [1 2 0]
+        // [0 0 3]
+        let a = SparseMatrix::from_entries(vec![(0, 0, 1.0), (0, 1, 2.0), (1, 2, 3.0)], 2, 3);
+        let at = a.transpose();
+        assert_eq!((at.n_rows, at.n_cols), (3, 2));
+        assert_eq!(at.entries, vec![(0, 0, 1.0), (1, 0, 2.0), (2, 1, 3.0)]);
+
+        let aat = a.matmul(&at).unwrap();
+        assert_eq!(aat.entries, vec![(0, 0, 5.0), (1, 1, 9.0)]);
+        assert!(a.matmul(&a).is_err());
+
+        let mut twice = a.clone();
+        twice.scale(2.0);
+        let zero = a.linear_combination(2.0, &twice, -1.0).unwrap();
+        assert!(zero.entries.iter().all(|(_row, _col, value)| *value == 0.0));
+
+        assert_eq!(a.diagonal(), vec![1.0, 0.0]);
+        assert_eq!(a.diagonal_value(1), None);
+        assert_eq!(a.row_entries(1), &[(1, 2, 3.0)]);
+        assert_eq!(a.column_entries(1), vec![(0, 1, 2.0)]);
+    }
+}
@@ -1,186 +1,1260 @@
-use crate::sparse_system::sparse_matrix::SparseMatrix;
-use rayon::prelude::*;
-use std::time::{Duration, Instant};
-
-pub struct SparseSystem<'a> {
-    coefficients: &'a SparseMatrix,
-    column: &'a Vec<f64>,
-}
-
-#[allow(dead_code)]
-#[derive(Debug)]
-pub struct SolverResult {
-    pub solution: Option<Vec<f64>>,
-    pub converged: bool,
-    pub diagonal_dominance: Option<bool>,
-    pub iters: usize,
-    pub tol: f64,
-    pub max_iters_reached: bool,
-    pub error: Option<f64>,
-    pub message: String,
-    pub elapsed_time: Option<Duration>,
-}
-
-impl<'a> SparseSystem<'a> {
-    pub fn new(matrix: &'a SparseMatrix, column: &'a Vec<f64>) -> SparseSystem<'a> {
-        SparseSystem {
-            coefficients: matrix,
-            column,
-        }
-    }
-
-    pub fn error_sq(&self, x: &[f64]) -> f64 {
-        self.coefficients
-            .dot(&x)
-            .unwrap()
-            .iter()
-            .zip(self.column.iter())
-            .map(|(axi, bi)| (axi - bi).powi(2))
-            .sum()
-    }
-
-    pub fn is_gauss_seidel_convergent(&self) -> bool {
-        // diagonally dominant
-
-        let mut diagonal = vec![0.0; self.coefficients.n_rows];
-        let mut off_diagonal = vec![0.0; self.coefficients.n_rows];
-
-        self.coefficients
-            .diagonal_entries()
-            .for_each(|(row, _col, value)| diagonal[*row] = value.abs());
-
-        self.coefficients
-            .off_diagonal_entries()
-            .for_each(|(row, _col, value)| off_diagonal[*row] += value.abs());
-
-        diagonal
-            .iter()
-            .zip(off_diagonal.iter())
-            .all(|(d, od)| d >= od)
-    }
-
-    pub fn gauss_seidel_solve(&self, x0: &Vec<f64>, tol: f64, max_iters: usize) -> SolverResult {
-        if x0.len() != self.coefficients.n_rows {
-            return SolverResult {
-                solution: None,
-                converged: false,
-                diagonal_dominance: None,
-                iters: 0,
-                tol,
-                max_iters_reached: false,
-                error: None,
-                message: format!(
-                    "Wrong dimensions [x0]={}    [A]={}x{}     [b]={}",
-                    x0.len(),
-                    self.coefficients.n_rows,
-                    self.coefficients.n_cols,
-                    self.column.len()
-                ),
-                elapsed_time: None,
-            };
-        }
-
-        if !self.is_gauss_seidel_convergent() {
-            return SolverResult {
-                solution: None,
-                converged: false,
-                diagonal_dominance: Some(false),
-                iters: 0,
-                tol,
-                max_iters_reached: false,
-                error: None,
-                message: "The coefficients matrix is not diagonally dominant".to_string(),
-                elapsed_time: None,
-            };
-        }
-
-        let start = Instant::now();
-        let mut x = x0.clone();
-        let mut sum_rows = vec![0.0; self.coefficients.n_rows];
-        let diagonal: Vec<f64> = self.coefficients.diagonal_values().collect();
-
-        for iter in 0..max_iters {
-            sum_rows.fill(0.0);
-
-            for (row, col, value) in self.coefficients.off_diagonal_entries() {
-                sum_rows[*row] += (*value) * x[*col];
-            }
-
-            x.par_iter_mut().enumerate().for_each(|(i, x_val)| {
-                *x_val = (self.column[i] - sum_rows[i]) / diagonal[i];
-            });
-
-            if self.error_sq(&x) < tol {
-                return SolverResult {
-                    error: Some(self.error_sq(&x)),
-                    solution: Some(x),
-                    converged: true,
-                    diagonal_dominance: Some(true),
-                    iters: iter + 1,
-                    tol,
-                    max_iters_reached: false,
-                    message: format!("Converged in {} iterations", iter + 1),
-                    elapsed_time: Some(start.elapsed()),
-                };
-            }
-        }
-
-        SolverResult {
-            error: Some(self.error_sq(&x)),
-            solution: Some(x),
-            converged: true,
-            diagonal_dominance: Some(true),
-            iters: max_iters,
-            tol,
-            max_iters_reached: true,
-            message: format!(
-                "Converged in {} iterations (max iterations reached)",
-                max_iters
-            ),
-            elapsed_time: Some(start.elapsed()),
-        }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_dot_product() {
-        let n_rows = 1000;
-        let n_entries = 100;
-        let force_diagonal = false;
-        let a = SparseMatrix::random(n_rows, n_entries, force_diagonal);
-        let b = a.random_vec_like();
-        let res = a.dot(&b);
-        assert!(
-            res.clone()
-                .map_err(|e| format!("Error from dot: {}", e))
-                .is_ok(),
-            "{}",
-            res.unwrap_err()
-        );
-        let res = a.dot_par(&b);
-        assert!(
-            res.clone()
-                .map_err(|e| format!("Error from dot_par: {}", e))
-                .is_ok(),
-            "{}",
-            res.unwrap_err()
-        );
-    }
-
-    #[test]
-    fn test_dot_product_2() {
-        let rows = vec![0, 1, 2, 2];
-        let cols = vec![0, 1, 2, 1];
-        let values = vec![1.0, 3.0, 5.0, 7.0];
-        let a = SparseMatrix::from_vecs(&rows, &cols, &values);
-        let b = vec![1.0, 1.0, 1.0];
-        let actual = a.dot(&b);
-        assert!(actual.is_ok());
-        let expected = vec![1.0, 3.0, 12.0];
-        assert_eq!(actual.unwrap(), expected);
-    }
-}
+use crate::sparse_system::block_matrix::BlockSparseMatrix;
+use crate::sparse_system::direct::SparseLu;
+use crate::sparse_system::kernels::{axpy, dot, norm_sq, residual_norm_sq, xpby, Scalar};
+use crate::sparse_system::linear_operator::LinearOperator;
+use crate::sparse_system::mixed_precision::{bicgstab_f32, CsrMatrixF32};
+use crate::sparse_system::multigrid::Multigrid;
+use crate::sparse_system::preconditioner::{BlockJacobiPreconditioner, Preconditioner};
+use crate::sparse_system::reordering::Colouring;
+use crate::sparse_system::sparse_matrix::{MatrixRows, SparseMatrix};
+use rayon::prelude::*;
+use std::time::{Duration, Instant};
+
+pub struct SparseSystem<'a, M: LinearOperator + ?Sized = SparseMatrix> {
+    coefficients: &'a M,
+    column: &'a Vec<f64>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct SolverResult {
+    pub solution: Option<Vec<f64>>,
+    pub converged: bool,
+    pub diagonal_dominance: Option<bool>,
+    pub iters: usize,
+    pub tol: f64,
+    pub max_iters_reached: bool,
+    pub error: Option<f64>,
+    pub message: String,
+    pub elapsed_time: Option<Duration>,
+    /// Squared residual norm after each iteration, same measure as `error`
+    pub residual_history: Vec<f64>,
+}
+
+impl SolverResult {
+    pub fn failure(tol: f64, diagonal_dominance: Option<bool>, message: String) -> SolverResult {
+        SolverResult {
+            solution: None,
+            converged: false,
+            diagonal_dominance,
+            iters: 0,
+            tol,
+            max_iters_reached: false,
+            error: None,
+            message,
+            elapsed_time: None,
+            residual_history: Vec::new(),
+        }
+    }
+}
+
+/// Preconditioned BiCGSTAB iterations on vectors of either precision, shared by
+/// `bicgstab_solve` and the single precision inner solves. `x` and `r` hold the initial guess
+/// and its residual and are updated in place, and the squared residual of every iteration is
+/// pushed to `history`. Returns the iterations done and the reason of a breakdown.
+pub(crate) fn bicgstab<T: Scalar>(
+    apply: impl Fn(&[T], &mut [T]),
+    precondition: impl Fn(&[T], &mut [T]),
+    x: &mut [T],
+    r: &mut [T],
+    tol: f64,
+    max_iters: usize,
+    history: &mut Vec<f64>,
+) -> (usize, Option<String>) {
+    let n = x.len();
+    let r_hat = r.to_vec();
+    let (mut rho, mut alpha, mut omega) = (1.0, 1.0, 1.0);
+    let mut v = vec![T::default(); n];
+    let mut t = vec![T::default(); n];
+    let mut p = vec![T::default(); n];
+    let mut p_hat = vec![T::default(); n];
+    let mut s_hat = vec![T::default(); n];
+
+    for iter in 0..max_iters {
+        let rho_new = dot(&r_hat, r);
+        if rho_new == 0.0 || omega == 0.0 {
+            let message = format!(
+                "Breakdown at iteration {}: rho = {:e}, omega = {:e}",
+                iter + 1,
+                rho_new,
+                omega
+            );
+            return (iter, Some(message));
+        }
+
+        let beta = T::from_f64((rho_new / rho) * (alpha / omega));
+        let omega_t = T::from_f64(omega);
+        rho = rho_new;
+        p.par_iter_mut()
+            .zip(r.par_iter().zip(v.par_iter()))
+            .for_each(|(pi, (ri, vi))| *pi = *ri + beta * (*pi - omega_t * *vi));
+
+        precondition(&p, &mut p_hat);
+        apply(&p_hat, &mut v);
+        let r_hat_v = dot(&r_hat, &v);
+        if r_hat_v == 0.0 {
+            return (
+                iter,
+                Some(format!("Breakdown at iteration {}: r_hat.v = 0", iter + 1)),
+            );
+        }
+        alpha = rho / r_hat_v;
+
+        // s is stored in r
+        axpy(-alpha, &v, r);
+        let s_norm = dot(r, r);
+        if s_norm < tol {
+            axpy(alpha, &p_hat, x);
+            history.push(s_norm);
+            return (iter + 1, None);
+        }
+
+        precondition(r, &mut s_hat);
+        apply(&s_hat, &mut t);
+        let t_norm = dot(&t, &t);
+        if t_norm == 0.0 {
+            // The half step is still valid, r holds its residual s
+            axpy(alpha, &p_hat, x);
+            history.push(s_norm);
+            return (
+                iter + 1,
+                Some(format!("Breakdown at iteration {}: t.t = 0", iter + 1)),
+            );
+        }
+        omega = dot(&t, r) / t_norm;
+
+        axpy(alpha, &p_hat, x);
+        axpy(omega, &s_hat, x);
+        axpy(-omega, &t, r);
+
+        let error = dot(r, r);
+        history.push(error);
+        if error < tol {
+            return (iter + 1, None);
+        }
+    }
+
+    (max_iters, None)
+}
+
+impl<'a, M: LinearOperator + ?Sized> SparseSystem<'a, M> {
+    pub fn new(matrix: &'a M, column: &'a Vec<f64>) -> SparseSystem<'a, M> {
+        SparseSystem {
+            coefficients: matrix,
+            column,
+        }
+    }
+
+    pub fn matrix(&self) -> &'a M {
+        self.coefficients
+    }
+
+    pub fn n_rows(&self) -> usize {
+        self.coefficients.dimensions().0
+    }
+
+    pub fn error_sq(&self, x: &[f64]) -> f64 {
+        self.coefficients
+            .apply(x)
+            .unwrap()
+            .iter()
+            .zip(self.column.iter())
+            .map(|(axi, bi)| (axi - bi).powi(2))
+            .sum()
+    }
+
+    pub fn residual(&self, x: &[f64]) -> Vec<f64> {
+        self.coefficients
+            .apply(x)
+            .unwrap()
+            .iter()
+            .zip(self.column.iter())
+            .map(|(axi, bi)| bi - axi)
+            .collect()
+    }
+
+    pub(crate) fn check_dimensions(&self, x0: &[f64]) -> Result<(), String> {
+        let (n_rows, n_cols) = self.coefficients.dimensions();
+        if x0.len() == n_rows && self.column.len() == n_rows && n_cols == n_rows {
+            return Ok(());
+        }
+
+        Err(format!(
+            "Wrong dimensions [x0]={}    [A]={}x{}     [b]={}",
+            x0.len(),
+            n_rows,
+            n_cols,
+            self.column.len()
+        ))
+    }
+
+    fn iteration_result(
+        &self,
+        x: Vec<f64>,
+        iters: usize,
+        tol: f64,
+        max_iters: usize,
+        start: Instant,
+        residual_history: Vec<f64>,
+    ) -> SolverResult {
+        let error = self.error_sq(&x);
+        let converged = error < tol;
+        let message = if converged {
+            format!("Converged in {} iterations", iters)
+        } else {
+            format!(
+                "Not converged after {} iterations (error {:e} >= tol {:e})",
+                iters, error, tol
+            )
+        };
+
+        SolverResult {
+            error: Some(error),
+            solution: Some(x),
+            converged,
+            diagonal_dominance: Some(true),
+            iters,
+            tol,
+            max_iters_reached: !converged && iters >= max_iters,
+            message,
+            elapsed_time: Some(start.elapsed()),
+            residual_history,
+        }
+    }
+
+    /// Preconditioned Conjugate Gradient, for symmetric positive-definite matrices
+    pub fn conjugate_gradient_solve(
+        &self,
+        x0: &[f64],
+        preconditioner: &dyn Preconditioner,
+        tol: f64,
+        max_iters: usize,
+    ) -> SolverResult {
+        if let Err(message) = self.check_dimensions(x0) {
+            return SolverResult::failure(tol, None, message);
+        }
+
+        let start = Instant::now();
+        let n = self.n_rows();
+        let mut x = x0.to_vec();
+        let mut r = self.residual(&x);
+        let mut z = vec![0.0; n];
+        let mut history = vec![norm_sq(&r)];
+
+        if history[0] < tol {
+            return self.krylov_result(x, 0, tol, max_iters, start, history);
+        }
+
+        preconditioner.apply(&r, &mut z);
+        let mut p = z.clone();
+        let mut rz = dot(&r, &z);
+
+        for iter in 0..max_iters {
+            let ap = self.coefficients.apply(&p).unwrap();
+            let pap = dot(&p, &ap);
+
+            if pap <= 0.0 {
+                let message = format!(
+                    "Breakdown at iteration {}: p^T A p = {:e}, the matrix is not positive definite",
+                    iter + 1,
+                    pap
+                );
+                return self.breakdown_result(x, iter, tol, start, history, message);
+            }
+
+            let alpha = rz / pap;
+            axpy(alpha, &p, &mut x);
+            axpy(-alpha, &ap, &mut r);
+            let error = norm_sq(&r);
+            history.push(error);
+
+            if error < tol {
+                return self.krylov_result(x, iter + 1, tol, max_iters, start, history);
+            }
+
+            preconditioner.apply(&r, &mut z);
+            let rz_new = dot(&r, &z);
+            let beta = rz_new / rz;
+            rz = rz_new;
+            xpby(&z, beta, &mut p);
+        }
+
+        self.krylov_result(x, max_iters, tol, max_iters, start, history)
+    }
+
+    /// Right-preconditioned BiCGSTAB, for general non-symmetric matrices
+    pub fn bicgstab_solve(
+        &self,
+        x0: &[f64],
+        preconditioner: &dyn Preconditioner,
+        tol: f64,
+        max_iters: usize,
+    ) -> SolverResult {
+        if let Err(message) = self.check_dimensions(x0) {
+            return SolverResult::failure(tol, None, message);
+        }
+
+        let start = Instant::now();
+        let mut x = x0.to_vec();
+        let mut r = self.residual(&x);
+        let mut history = vec![norm_sq(&r)];
+
+        if history[0] < tol {
+            return self.krylov_result(x, 0, tol, max_iters, start, history);
+        }
+
+        let apply = |x: &[f64], y: &mut [f64]| {
+            y.copy_from_slice(&self.coefficients.apply(x).unwrap());
+        };
+        let precondition = |r: &[f64], z: &mut [f64]| preconditioner.apply(r, z);
+        match bicgstab(
+            apply,
+            precondition,
+            &mut x,
+            &mut r,
+            tol,
+            max_iters,
+            &mut history,
+        ) {
+            (iters, None) => self.krylov_result(x, iters, tol, max_iters, start, history),
+            (iters, Some(message)) => self.breakdown_result(x, iters, tol, start, history, message),
+        }
+    }
+
+    /// Right-preconditioned GMRES restarted every `restart` iterations
+    pub fn gmres_solve(
+        &self,
+        x0: &[f64],
+        preconditioner: &dyn Preconditioner,
+        restart: usize,
+        tol: f64,
+        max_iters: usize,
+    ) -> SolverResult {
+        if let Err(message) = self.check_dimensions(x0) {
+            return SolverResult::failure(tol, None, message);
+        }
+
+        let start = Instant::now();
+        let n = self.n_rows();
+        let m = restart.max(1).min(n.max(1));
+        let mut x = x0.to_vec();
+        let mut r = self.residual(&x);
+        let mut history = vec![norm_sq(&r)];
+        let mut iters = 0;
+
+        if history[0] < tol {
+            return self.krylov_result(x, 0, tol, max_iters, start, history);
+        }
+
+        let mut basis: Vec<Vec<f64>> = Vec::with_capacity(m + 1);
+        let mut z = vec![0.0; n];
+
+        while iters < max_iters {
+            let beta = norm_sq(&r).sqrt();
+            basis.clear();
+            basis.push(r.iter().map(|ri| ri / beta).collect());
+
+            // Hessenberg matrix stored by columns, already rotated into upper triangular form
+            let mut h: Vec<Vec<f64>> = Vec::with_capacity(m);
+            let mut cs: Vec<f64> = Vec::with_capacity(m);
+            let mut sn: Vec<f64> = Vec::with_capacity(m);
+            let mut g = vec![0.0; m + 1];
+            g[0] = beta;
+
+            let mut k = 0;
+            while k < m && iters < max_iters {
+                preconditioner.apply(&basis[k], &mut z);
+                let mut w = self.coefficients.apply(&z).unwrap();
+
+                // Modified Gram-Schmidt
+                let mut column = vec![0.0; k + 2];
+                for (j, v) in basis.iter().enumerate() {
+                    column[j] = dot(&w, v);
+                    axpy(-column[j], v, &mut w);
+                }
+                column[k + 1] = dot(&w, &w).sqrt();
+
+                for j in 0..k {
+                    let (a, b) = (column[j], column[j + 1]);
+                    column[j] = cs[j] * a + sn[j] * b;
+                    column[j + 1] = -sn[j] * a + cs[j] * b;
+                }
+
+                let denominator = column[k].hypot(column[k + 1]);
+                let (c, s) = if denominator == 0.0 {
+                    (1.0, 0.0)
+                } else {
+                    (column[k] / denominator, column[k + 1] / denominator)
+                };
+                column[k] = denominator;
+                column[k + 1] = 0.0;
+                g[k + 1] = -s * g[k];
+                g[k] *= c;
+                cs.push(c);
+                sn.push(s);
+
+                let happy_breakdown = column[k] == 0.0 || dot(&w, &w) == 0.0;
+                if !happy_breakdown {
+                    let w_norm = dot(&w, &w).sqrt();
+                    basis.push(w.into_iter().map(|wi| wi / w_norm).collect());
+                }
+
+                h.push(column);
+                k += 1;
+                iters += 1;
+                history.push(g[k] * g[k]);
+
+                if g[k] * g[k] < tol || happy_breakdown {
+                    break;
+                }
+            }
+
+            // Back substitution for y in H y = g, then x += M^-1 V y
+            let mut y = vec![0.0; k];
+            for i in (0..k).rev() {
+                let sum: f64 = ((i + 1)..k).map(|j| h[j][i] * y[j]).sum();
+                y[i] = if h[i][i] == 0.0 {
+                    0.0
+                } else {
+                    (g[i] - sum) / h[i][i]
+                };
+            }
+
+            let mut update = vec![0.0; n];
+            for (yi, v) in y.iter().zip(basis.iter()) {
+                axpy(*yi, v, &mut update);
+            }
+            preconditioner.apply(&update, &mut z);
+            axpy(1.0, &z, &mut x);
+
+            r = self.residual(&x);
+            let error = norm_sq(&r);
+            if let Some(last) = history.last_mut() {
+                *last = error;
+            }
+
+            if error < tol {
+                return self.krylov_result(x, iters, tol, max_iters, start, history);
+            }
+        }
+
+        self.krylov_result(x, iters, tol, max_iters, start, history)
+    }
+
+    /// Standalone multigrid, one V-cycle per iteration
+    pub fn multigrid_solve(
+        &self,
+        x0: &[f64],
+        multigrid: &Multigrid,
+        tol: f64,
+        max_iters: usize,
+    ) -> SolverResult {
+        if let Err(message) = self.check_dimensions(x0) {
+            return SolverResult::failure(tol, None, message);
+        }
+
+        if multigrid.n_rows() != self.n_rows() {
+            let message = format!(
+                "Multigrid hierarchy built for {} rows, system has {}",
+                multigrid.n_rows(),
+                self.n_rows()
+            );
+            return SolverResult::failure(tol, None, message);
+        }
+
+        let start = Instant::now();
+        let mut x = x0.to_vec();
+        let mut history = vec![self.error_sq(&x)];
+
+        if history[0] < tol {
+            return self.krylov_result(x, 0, tol, max_iters, start, history);
+        }
+
+        for iter in 0..max_iters {
+            multigrid.v_cycle(0, self.column, &mut x);
+
+            let error = self.error_sq(&x);
+            history.push(error);
+            if error < tol {
+                return self.krylov_result(x, iter + 1, tol, max_iters, start, history);
+            }
+        }
+
+        self.krylov_result(x, max_iters, tol, max_iters, start, history)
+    }
+
+    fn krylov_result(
+        &self,
+        x: Vec<f64>,
+        iters: usize,
+        tol: f64,
+        max_iters: usize,
+        start: Instant,
+        residual_history: Vec<f64>,
+    ) -> SolverResult {
+        let mut result = self.iteration_result(x, iters, tol, max_iters, start, residual_history);
+        result.diagonal_dominance = None;
+        result
+    }
+
+    fn breakdown_result(
+        &self,
+        x: Vec<f64>,
+        iters: usize,
+        tol: f64,
+        start: Instant,
+        residual_history: Vec<f64>,
+        message: String,
+    ) -> SolverResult {
+        let mut result = self.krylov_result(x, iters, tol, usize::MAX, start, residual_history);
+        result.converged = false;
+        result.message = message;
+        result
+    }
+}
+
+/// Methods that need the individual rows of the matrix
+impl<'a, M: MatrixRows> SparseSystem<'a, M> {
+    pub fn is_gauss_seidel_convergent(&self) -> bool {
+        // diagonally dominant
+
+        (0..self.coefficients.n_rows()).all(|row| {
+            let (diagonal, off_diagonal) =
+                self.coefficients
+                    .row(row)
+                    .fold((0.0, 0.0), |(d, od): (f64, f64), (col, value)| {
+                        if col == row {
+                            (d + value, od)
+                        } else {
+                            (d, od + value.abs())
+                        }
+                    });
+            diagonal.abs() >= off_diagonal
+        })
+    }
+
+    fn row_diagonal(&self) -> Result<Vec<f64>, String> {
+        let diagonal: Vec<f64> = (0..self.coefficients.n_rows())
+            .map(|row| {
+                self.coefficients
+                    .row(row)
+                    .filter(|(col, _value)| *col == row)
+                    .map(|(_col, value)| value)
+                    .sum()
+            })
+            .collect();
+
+        match diagonal.iter().position(|d| *d == 0.0) {
+            None => Ok(diagonal),
+            Some(row) => Err(format!("Zero or missing diagonal entry in row {}", row)),
+        }
+    }
+
+    /// Checks shared by the stationary methods, returning the diagonal of the matrix
+    fn prepare_stationary(&self, x0: &[f64], tol: f64) -> Result<Vec<f64>, Box<SolverResult>> {
+        if let Err(message) = self.check_dimensions(x0) {
+            return Err(Box::new(SolverResult::failure(tol, None, message)));
+        }
+
+        if !self.is_gauss_seidel_convergent() {
+            let message = "The coefficients matrix is not diagonally dominant".to_string();
+            return Err(Box::new(SolverResult::failure(tol, Some(false), message)));
+        }
+
+        self.row_diagonal()
+            .map_err(|message| Box::new(SolverResult::failure(tol, Some(true), message)))
+    }
+
+    /// Relaxed Gauss-Seidel value of a single row, using the latest values of `x`
+    fn relax_row(&self, x: &[f64], row: usize, diagonal: f64, omega: f64) -> f64 {
+        let off_diagonal_sum: f64 = self
+            .coefficients
+            .row(row)
+            .filter(|(col, _value)| *col != row)
+            .map(|(col, value)| value * x[col])
+            .sum();
+
+        let gauss_seidel = (self.column[row] - off_diagonal_sum) / diagonal;
+        x[row] + omega * (gauss_seidel - x[row])
+    }
+
+    /// Relaxes every row of one colour. Rows of the same colour are not coupled, so their
+    /// updates are computed in parallel from the same `x` into `updates`.
+    fn relax_colour(
+        &self,
+        x: &mut [f64],
+        rows: &[usize],
+        diagonal: &[f64],
+        omega: f64,
+        updates: &mut Vec<f64>,
+    ) {
+        rows.par_iter()
+            .map(|row| self.relax_row(x, *row, diagonal[*row], omega))
+            .collect_into_vec(updates);
+        for (row, value) in rows.iter().zip(updates.iter()) {
+            x[*row] = *value;
+        }
+    }
+
+    pub fn jacobi_solve(&self, x0: &[f64], tol: f64, max_iters: usize) -> SolverResult {
+        let diagonal = match self.prepare_stationary(x0, tol) {
+            Ok(diagonal) => diagonal,
+            Err(result) => return *result,
+        };
+
+        let start = Instant::now();
+        let mut x = x0.to_vec();
+        let mut history = vec![self.error_sq(&x)];
+        let mut x_old = x.clone();
+
+        for iter in 0..max_iters {
+            x_old.copy_from_slice(&x);
+
+            x.par_iter_mut().enumerate().for_each(|(i, x_val)| {
+                let sum_row: f64 = self
+                    .coefficients
+                    .row(i)
+                    .filter(|(col, _value)| *col != i)
+                    .map(|(col, value)| value * x_old[col])
+                    .sum();
+                *x_val = (self.column[i] - sum_row) / diagonal[i];
+            });
+
+            let error = residual_norm_sq(self.coefficients, &x, self.column);
+            history.push(error);
+            if error < tol {
+                return self.iteration_result(x, iter + 1, tol, max_iters, start, history);
+            }
+        }
+
+        self.iteration_result(x, max_iters, tol, max_iters, start, history)
+    }
+
+    /// Colours the matrix on every call, solvers run repeatedly on the same pattern keep it
+    /// with `multicolour_solve` or `linear_solver::GaussSeidelSolver`
+    // One-off solves only, the configured solvers of linear_solver go through multicolour_solve
+    #[allow(dead_code)]
+    pub fn gauss_seidel_solve(&self, x0: &[f64], tol: f64, max_iters: usize) -> SolverResult {
+        self.coloured_solve(x0, 1.0, false, tol, max_iters)
+    }
+
+    /// Successive over-relaxation: in-place Gauss-Seidel sweeps with relaxation factor `omega`.
+    /// The rows are swept colour by colour of a greedy colouring of the matrix graph, so the
+    /// rows within a colour are relaxed in parallel.
+    // Like gauss_seidel_solve, SorSolver keeps its colouring instead
+    #[allow(dead_code)]
+    pub fn sor_solve(&self, x0: &[f64], omega: f64, tol: f64, max_iters: usize) -> SolverResult {
+        self.coloured_solve(x0, omega, false, tol, max_iters)
+    }
+
+    /// Symmetric SOR: a forward sweep followed by a backward sweep per iteration
+    // Like gauss_seidel_solve, SsorSolver keeps its colouring instead
+    #[allow(dead_code)]
+    pub fn ssor_solve(&self, x0: &[f64], omega: f64, tol: f64, max_iters: usize) -> SolverResult {
+        self.coloured_solve(x0, omega, true, tol, max_iters)
+    }
+
+    /// `multicolour_solve` with a colouring built for this system only
+    fn coloured_solve(
+        &self,
+        x0: &[f64],
+        omega: f64,
+        symmetric: bool,
+        tol: f64,
+        max_iters: usize,
+    ) -> SolverResult {
+        if let Err(message) = self.check_dimensions(x0) {
+            return SolverResult::failure(tol, None, message);
+        }
+
+        match Colouring::new(self.coefficients) {
+            Ok(colouring) => {
+                self.multicolour_solve(x0, &colouring, omega, symmetric, tol, max_iters)
+            }
+            Err(message) => SolverResult::failure(tol, None, message),
+        }
+    }
+
+    /// SOR, or SSOR when `symmetric`, swept along a colouring built beforehand, so systems
+    /// sharing a matrix pattern colour it once
+    pub fn multicolour_solve(
+        &self,
+        x0: &[f64],
+        colouring: &Colouring,
+        omega: f64,
+        symmetric: bool,
+        tol: f64,
+        max_iters: usize,
+    ) -> SolverResult {
+        if !(omega > 0.0 && omega < 2.0) {
+            let message = format!("Relaxation factor must be in (0, 2), got {}", omega);
+            return SolverResult::failure(tol, None, message);
+        }
+
+        let diagonal = match self.prepare_stationary(x0, tol) {
+            Ok(diagonal) => diagonal,
+            Err(result) => return *result,
+        };
+
+        if !colouring.fits(self.coefficients) {
+            let message = "Colouring does not fit the matrix pattern".to_string();
+            return SolverResult::failure(tol, None, message);
+        }
+
+        let start = Instant::now();
+        let mut x = x0.to_vec();
+        let mut updates = Vec::new();
+        let mut history = vec![self.error_sq(&x)];
+
+        for iter in 0..max_iters {
+            for rows in colouring.colours.iter() {
+                self.relax_colour(&mut x, rows, &diagonal, omega, &mut updates);
+            }
+
+            if symmetric {
+                for rows in colouring.colours.iter().rev() {
+                    self.relax_colour(&mut x, rows, &diagonal, omega, &mut updates);
+                }
+            }
+
+            let error = residual_norm_sq(self.coefficients, &x, self.column);
+            history.push(error);
+            if error < tol {
+                return self.iteration_result(x, iter + 1, tol, max_iters, start, history);
+            }
+        }
+
+        self.iteration_result(x, max_iters, tol, max_iters, start, history)
+    }
+
+    /// Sparse LU with partial pivoting, exact up to round-off. Meant for small systems and as
+    /// a reference for the iterative solvers.
+    pub fn direct_solve(&self, tol: f64) -> SolverResult {
+        let n = self.coefficients.n_rows();
+        if let Err(message) = self.check_dimensions(&vec![0.0; n]) {
+            return SolverResult::failure(tol, None, message);
+        }
+
+        let start = Instant::now();
+        let x = match SparseLu::new(self.coefficients).and_then(|lu| lu.solve(self.column)) {
+            Ok(x) => x,
+            Err(message) => return SolverResult::failure(tol, None, message),
+        };
+
+        let error = self.error_sq(&x);
+        self.krylov_result(x, 1, tol, 1, start, vec![error])
+    }
+
+    /// Iterative refinement: every correction is solved in single precision, by BiCGSTAB on
+    /// a `CsrMatrixF32` copy of the matrix to a relative accuracy of `inner_reduction`, while
+    /// the residuals use the original matrix in double precision. The result is as accurate
+    /// as a double precision solve for well conditioned systems. `max_iters` bounds the
+    /// total inner iterations and the history holds one entry per refinement step.
+    pub fn mixed_precision_solve(
+        &self,
+        x0: &[f64],
+        inner_reduction: f64,
+        tol: f64,
+        max_iters: usize,
+    ) -> SolverResult {
+        if let Err(message) = self.check_dimensions(x0) {
+            return SolverResult::failure(tol, None, message);
+        }
+
+        let start = Instant::now();
+        let single = match CsrMatrixF32::from_rows(self.coefficients) {
+            Ok(single) => single,
+            Err(message) => return SolverResult::failure(tol, None, message),
+        };
+        let inverse_diagonal = match single.inverse_diagonal() {
+            Ok(inverse_diagonal) => inverse_diagonal,
+            Err(message) => return SolverResult::failure(tol, None, message),
+        };
+
+        let mut x = x0.to_vec();
+        let mut r = self.residual(&x);
+        let mut history = vec![norm_sq(&r)];
+        let mut iters = 0;
+
+        while *history.last().unwrap() >= tol && iters < max_iters {
+            // Scaled to unit size so the residual neither underflows nor overflows in f32
+            let scale = r.iter().fold(0.0, |m: f64, ri| m.max(ri.abs()));
+            if scale == 0.0 {
+                // Exact solution, nothing left to refine
+                break;
+            }
+            let r_single: Vec<f32> = r.iter().map(|ri| (ri / scale) as f32).collect();
+            let (correction, used) = bicgstab_f32(
+                &single,
+                &inverse_diagonal,
+                &r_single,
+                inner_reduction * inner_reduction,
+                max_iters - iters,
+            );
+            iters += used.max(1);
+
+            x.iter_mut()
+                .zip(correction.iter())
+                .for_each(|(xi, di)| *xi += scale * *di as f64);
+            r = self.residual(&x);
+            let error = norm_sq(&r);
+            let stagnated = error >= *history.last().unwrap();
+            history.push(error);
+
+            if stagnated {
+                let message = format!(
+                    "Refinement stagnated after {} inner iterations (error {:e} >= tol {:e})",
+                    iters, error, tol
+                );
+                return self.breakdown_result(x, iters, tol, start, history, message);
+            }
+        }
+
+        self.krylov_result(x, iters, tol, max_iters, start, history)
+    }
+}
+
+impl<'a> SparseSystem<'a, BlockSparseMatrix> {
+    /// Coupled solve of all the variables at once: GMRES preconditioned by the inverse of
+    /// the diagonal blocks
+    // Alternative to the segregated solves of LinearSolvers, no solver loop picks it yet
+    #[allow(dead_code)]
+    pub fn coupled_solve(
+        &self,
+        x0: &[f64],
+        restart: usize,
+        tol: f64,
+        max_iters: usize,
+    ) -> SolverResult {
+        match BlockJacobiPreconditioner::new(self.coefficients) {
+            Ok(preconditioner) => self.gmres_solve(x0, &preconditioner, restart, tol, max_iters),
+            Err(message) => SolverResult::failure(tol, None, message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_system::csr_matrix::CsrMatrix;
+    use crate::sparse_system::multigrid::AmgParameters;
+    use crate::sparse_system::preconditioner::{
+        Ic0Preconditioner, IdentityPreconditioner, Ilu0Preconditioner, JacobiPreconditioner,
+    };
+    use crate::sparse_system::reordering::Permutation;
+    use crate::sparse_system::sparse_matrix::SparseMatrixBuilder;
+    use crate::sparse_system::test_matrices::{laplacian_1d, laplacian_2d, tridiagonal};
+
+    #[test]
+    fn test_dot_product() {
+        let n_rows = 1000;
+        let n_entries = 100;
+        let force_diagonal = false;
+        let a = SparseMatrix::random(n_rows, n_entries, force_diagonal);
+        let b = a.random_vec_like();
+        let res = a.dot(&b);
+        assert!(
+            res.clone()
+                .map_err(|e| format!("Error from dot: {}", e))
+                .is_ok(),
+            "{}",
+            res.unwrap_err()
+        );
+        let res = a.dot_par(&b);
+        assert!(
+            res.clone()
+                .map_err(|e| format!("Error from dot_par: {}", e))
+                .is_ok(),
+            "{}",
+            res.unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_dot_product_2() {
+        let rows = vec![0, 1, 2, 2];
+        let cols = vec![0, 1, 2, 1];
+        let values = vec![1.0, 3.0, 5.0, 7.0];
+        let a = SparseMatrix::from_vecs(&rows, &cols, &values);
+        let b = vec![1.0, 1.0, 1.0];
+        let actual = a.dot(&b);
+        assert!(actual.is_ok());
+        let expected = vec![1.0, 3.0, 12.0];
+        assert_eq!(actual.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_builder_sums_duplicates_and_reuses_pattern() {
+        // Face loop over a 1D chain of 3 cells, every face touches 4 entries
+        let assemble = |builder: &mut dyn FnMut(usize, usize, f64)| {
+            for (owner, neighbour) in [(0, 1), (1, 2)] {
+                builder(owner, owner, 1.0);
+                builder(owner, neighbour, -1.0);
+                builder(neighbour, neighbour, 1.0);
+                builder(neighbour, owner, -1.0);
+            }
+        };
+
+        let mut builder = SparseMatrixBuilder::new(3, 3);
+        assemble(&mut |row, col, value| builder.add(row, col, value).unwrap());
+        let mut a = builder.build();
+        assert_eq!(a.entries.len(), 7);
+        assert_eq!(a.dot(&[1.0, 2.0, 4.0]).unwrap(), vec![-1.0, -1.0, 2.0]);
+
+        a.reset_values();
+        assemble(&mut |row, col, value| a.add_value(row, col, 2.0 * value).unwrap());
+        assert_eq!(a.entries.len(), 7);
+        assert_eq!(a.dot(&[1.0, 2.0, 4.0]).unwrap(), vec![-2.0, -2.0, 4.0]);
+        assert!(a.add_value(0, 2, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_matrix_market_round_trip() {
+        let path = std::env::temp_dir().join("climate_flow_matrix.mtx");
+
+        let a = SparseMatrix::random(50, 300, true);
+        a.save_matrix_market(&path, false).unwrap();
+        let loaded = SparseMatrix::load_matrix_market(&path).unwrap();
+        let x = a.random_vec_like();
+        for (expected, actual) in a.dot(&x).unwrap().iter().zip(loaded.dot(&x).unwrap()) {
+            assert!((expected - actual).abs() < 1e-9);
+        }
+        assert!(a.save_matrix_market(&path, true).is_err());
+
+        let laplacian = laplacian_2d(5, 0.0);
+        laplacian.save_matrix_market(&path, true).unwrap();
+        let header = std::fs::read_to_string(&path).unwrap();
+        assert!(header.starts_with("%%MatrixMarket matrix coordinate real symmetric"));
+        let loaded = SparseMatrix::load_matrix_market(&path).unwrap();
+        assert_eq!(loaded.entries, laplacian.entries);
+    }
+
+    #[test]
+    fn test_direct_solve_is_reference() {
+        let a = laplacian_2d(20, 2.0);
+        let b = a.random_vec_like();
+        let system = SparseSystem::new(&a, &b);
+
+        let direct = system.direct_solve(1e-20);
+        assert!(direct.converged, "{}", direct.message);
+        let gmres = system.gmres_solve(
+            &vec![0.0; a.n_rows],
+            &IdentityPreconditioner,
+            40,
+            1e-20,
+            500,
+        );
+        assert!(gmres.converged, "{}", gmres.message);
+        for (d, g) in direct
+            .solution
+            .unwrap()
+            .iter()
+            .zip(gmres.solution.unwrap().iter())
+        {
+            assert!((d - g).abs() < 1e-8);
+        }
+
+        let csr = CsrMatrix::from(&a);
+        let result = SparseSystem::new(&csr, &b).direct_solve(1e-20);
+        assert!(result.converged, "{}", result.message);
+    }
+
+    #[test]
+    fn test_mixed_precision_reaches_double_accuracy() {
+        let a = laplacian_2d(20, 2.0);
+        let b = a.random_vec_like();
+        let system = SparseSystem::new(&a, &b);
+
+        // Far below what single precision alone can resolve
+        let result = system.mixed_precision_solve(&vec![0.0; a.n_rows], 1e-3, 1e-22, 2000);
+        assert!(result.converged, "{}", result.message);
+        assert!(result.residual_history.len() > 2);
+        let direct = system.direct_solve(1e-20).solution.unwrap();
+        for (m, d) in result.solution.unwrap().iter().zip(direct.iter()) {
+            assert!((m - d).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_mixed_precision_exact_guess() {
+        let a = laplacian_1d(10);
+        let expected = vec![1.0; 10];
+        let b = a.dot(&expected).unwrap();
+        let system = SparseSystem::new(&a, &b);
+
+        // Nothing to refine, even against a tolerance no residual can meet
+        let result = system.mixed_precision_solve(&expected, 1e-3, 0.0, 100);
+        assert_eq!(result.iters, 0);
+        assert_eq!(result.solution, Some(expected));
+        assert_eq!(result.residual_history, vec![0.0]);
+    }
+
+    #[test]
+    fn test_rcm_permuted_system() {
+        // Scramble a 2D Laplacian, then let RCM recover a banded numbering
+        let a = laplacian_2d(15, 0.5);
+        let n = a.n_rows;
+        let scrambled =
+            Permutation::from_new_to_old((0..n).map(|i| (i * 31) % n).collect()).unwrap();
+        let a = a.permute(&scrambled).unwrap();
+        let b = a.random_vec_like();
+
+        let permutation = a.rcm_permutation().unwrap();
+        let reordered = a.permute(&permutation).unwrap();
+        assert!(reordered.bandwidth() < a.bandwidth());
+        assert!(reordered.bandwidth() <= 16);
+
+        let reordered_b = permutation.apply(&b);
+        let system = SparseSystem::new(&reordered, &reordered_b);
+        let result = system.gmres_solve(&vec![0.0; n], &IdentityPreconditioner, 30, 1e-20, 500);
+        assert!(result.converged, "{}", result.message);
+
+        let x = permutation.apply_inverse(&result.solution.unwrap());
+        let ax = a.dot(&x).unwrap();
+        for (ax_i, b_i) in ax.iter().zip(b.iter()) {
+            assert!((ax_i - b_i).abs() < 1e-8);
+        }
+    }
+
+    #[test]
+    fn test_stationary_solvers() {
+        let a = laplacian_1d(20);
+        let expected: Vec<f64> = (0..20).map(|i| (i as f64).sin()).collect();
+        let b = a.dot(&expected).unwrap();
+        let system = SparseSystem::new(&a, &b);
+        let x0 = vec![0.0; 20];
+
+        let jacobi = system.jacobi_solve(&x0, 1e-16, 20000);
+        let gauss_seidel = system.gauss_seidel_solve(&x0, 1e-16, 20000);
+        let sor = system.sor_solve(&x0, 1.7, 1e-16, 20000);
+        let ssor = system.ssor_solve(&x0, 1.5, 1e-16, 20000);
+
+        for result in [&jacobi, &gauss_seidel, &sor, &ssor] {
+            assert!(result.converged, "{}", result.message);
+            let solution = result.solution.as_ref().unwrap();
+            for (x, e) in solution.iter().zip(expected.iter()) {
+                assert!((x - e).abs() < 1e-6);
+            }
+        }
+
+        assert!(gauss_seidel.iters < jacobi.iters);
+        assert!(sor.iters < gauss_seidel.iters);
+
+        // Non-square systems fail before any colouring is built
+        let wide = SparseMatrix::from_entries(vec![(0, 0, 2.0), (0, 2, -1.0), (1, 1, 2.0)], 2, 3);
+        let b = vec![1.0; 2];
+        let system = SparseSystem::new(&wide, &b);
+        for result in [
+            system.gauss_seidel_solve(&[0.0; 3], 1e-16, 10),
+            system.sor_solve(&[0.0; 2], 1.5, 1e-16, 10),
+        ] {
+            assert!(result.solution.is_none());
+            assert!(result.message.starts_with("Wrong dimensions"));
+        }
+        assert!(Colouring::new(&wide).is_err());
+    }
+
+    #[test]
+    fn test_multicolour_sor_non_symmetric() {
+        let a = laplacian_2d(12, 2.0);
+        let n = a.n_rows;
+        let expected: Vec<f64> = (0..n).map(|i| (i as f64 * 0.3).sin()).collect();
+        let b = a.dot(&expected).unwrap();
+        let system = SparseSystem::new(&a, &b);
+        let x0 = vec![0.0; n];
+        let direct = system.direct_solve(1e-16).solution.unwrap();
+
+        let colouring = Colouring::new(&a).unwrap();
+        assert_eq!(colouring.colours.len(), 2);
+        for symmetric in [false, true] {
+            let result = system.multicolour_solve(&x0, &colouring, 1.2, symmetric, 1e-20, 5000);
+            assert!(result.converged, "{}", result.message);
+            let solution = result.solution.unwrap();
+            for (x, d) in solution.iter().zip(direct.iter()) {
+                assert!((x - d).abs() < 1e-9);
+            }
+        }
+
+        // Coupling two cells of the same colour breaks the colouring
+        let mut entries = a.entries.clone();
+        entries.push((0, 2, -0.1));
+        let coupled = SparseMatrix::from_entries(entries, n, n);
+        assert!(colouring.fits(&laplacian_2d(12, 0.5)));
+        assert!(!colouring.fits(&coupled));
+        let result = SparseSystem::new(&coupled, &b)
+            .multicolour_solve(&x0, &colouring, 1.2, false, 1e-20, 5000);
+        assert!(result.solution.is_none());
+    }
+
+    #[test]
+    fn test_conjugate_gradient() {
+        let n = 200;
+        let a = laplacian_1d(n);
+        let expected: Vec<f64> = (0..n).map(|i| (i as f64 * 0.1).cos()).collect();
+        let b = a.dot(&expected).unwrap();
+        let system = SparseSystem::new(&a, &b);
+        let jacobi = JacobiPreconditioner::new(&a).unwrap();
+
+        for preconditioner in [&IdentityPreconditioner as &dyn Preconditioner, &jacobi] {
+            let result = system.conjugate_gradient_solve(&vec![0.0; n], preconditioner, 1e-20, n);
+            assert!(result.converged, "{}", result.message);
+            let solution = result.solution.unwrap();
+            for (x, e) in solution.iter().zip(expected.iter()) {
+                assert!((x - e).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_non_symmetric_krylov_solvers() {
+        let n = 100;
+        // Upwind convection at a cell Peclet number of 3 makes the matrix non-symmetric
+        let a = tridiagonal(n, -4.0, 5.0, -1.0);
+        let expected: Vec<f64> = (0..n).map(|i| 1.0 + (i as f64 * 0.2).sin()).collect();
+        let b = a.dot(&expected).unwrap();
+        let system = SparseSystem::new(&a, &b);
+        let jacobi = JacobiPreconditioner::new(&a).unwrap();
+        let x0 = vec![0.0; n];
+
+        let results = [
+            system.bicgstab_solve(&x0, &IdentityPreconditioner, 1e-20, 1000),
+            system.bicgstab_solve(&x0, &jacobi, 1e-20, 1000),
+            system.gmres_solve(&x0, &IdentityPreconditioner, 30, 1e-20, 1000),
+            system.gmres_solve(&x0, &jacobi, 10, 1e-20, 1000),
+        ];
+
+        for result in results {
+            assert!(result.converged, "{}", result.message);
+            assert_eq!(result.residual_history.len(), result.iters + 1);
+            let solution = result.solution.unwrap();
+            for (x, e) in solution.iter().zip(expected.iter()) {
+                assert!((x - e).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_krylov_solvers_without_diagonal_dominance() {
+        // Central convection at a cell Peclet number of 4: off-diagonals outweigh the diagonal
+        let n = 60;
+        let a = tridiagonal(n, -3.0, 2.0, 1.0);
+        let expected: Vec<f64> = (0..n).map(|i| (i as f64 * 0.3).cos()).collect();
+        let b = a.dot(&expected).unwrap();
+        let system = SparseSystem::new(&a, &b);
+        assert!(!system.is_gauss_seidel_convergent());
+
+        let x0 = vec![0.0; n];
+        let ilu = Ilu0Preconditioner::new(&a).unwrap();
+        for result in [
+            system.bicgstab_solve(&x0, &IdentityPreconditioner, 1e-20, 1000),
+            system.gmres_solve(&x0, &IdentityPreconditioner, n, 1e-20, 1000),
+            system.bicgstab_solve(&x0, &ilu, 1e-20, 1000),
+        ] {
+            assert!(result.converged, "{}", result.message);
+            let solution = result.solution.unwrap();
+            for (x, e) in solution.iter().zip(expected.iter()) {
+                assert!((x - e).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bicgstab_breakdown() {
+        // r_hat is orthogonal to A r_hat on the first iteration
+        let a = SparseMatrix::from_vecs(&vec![0, 1], &vec![1, 0], &vec![1.0, 1.0]);
+        let b = vec![1.0, 0.0];
+        let system = SparseSystem::new(&a, &b);
+        let result = system.bicgstab_solve(&[0.0, 0.0], &IdentityPreconditioner, 1e-20, 100);
+        assert!(!result.converged);
+        assert!(!result.max_iters_reached);
+        assert!(result.message.contains("Breakdown"), "{}", result.message);
+        assert!(result.solution.unwrap().iter().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn test_incomplete_factorizations() {
+        let n = 30;
+        let b = vec![1.0; n * n];
+        let x0 = vec![0.0; n * n];
+
+        let a = laplacian_2d(n, 0.0);
+        let system = SparseSystem::new(&a, &b);
+        let plain = system.conjugate_gradient_solve(&x0, &IdentityPreconditioner, 1e-16, 1000);
+        let ic = Ic0Preconditioner::new(&a).unwrap();
+        let preconditioned = system.conjugate_gradient_solve(&x0, &ic, 1e-16, 1000);
+        assert!(preconditioned.converged, "{}", preconditioned.message);
+        assert!(preconditioned.iters < plain.iters);
+
+        let a = laplacian_2d(n, 2.0);
+        let system = SparseSystem::new(&a, &b);
+        let plain = system.gmres_solve(&x0, &IdentityPreconditioner, 30, 1e-16, 1000);
+        let ilu = Ilu0Preconditioner::new(&a).unwrap();
+        let preconditioned = system.gmres_solve(&x0, &ilu, 30, 1e-16, 1000);
+        assert!(plain.converged, "{}", plain.message);
+        assert!(preconditioned.converged, "{}", preconditioned.message);
+        assert!(preconditioned.iters * 4 < plain.iters);
+    }
+
+    #[test]
+    fn test_ic0_of_tridiagonal_is_exact() {
+        // No fill-in is dropped for a tridiagonal matrix
+        let a = laplacian_1d(10);
+        let x: Vec<f64> = (0..10).map(|i| i as f64).collect();
+        let b = a.dot(&x).unwrap();
+        let mut z = vec![0.0; 10];
+        Ic0Preconditioner::new(&a).unwrap().apply(&b, &mut z);
+        for (zi, xi) in z.iter().zip(x.iter()) {
+            assert!((zi - xi).abs() < 1e-10);
+        }
+        Ilu0Preconditioner::new(&a).unwrap().apply(&b, &mut z);
+        for (zi, xi) in z.iter().zip(x.iter()) {
+            assert!((zi - xi).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn test_algebraic_multigrid() {
+        let b = vec![1.0; 40 * 40];
+        let a = laplacian_2d(40, 0.0);
+        let system = SparseSystem::new(&a, &b);
+        let x0 = vec![0.0; 40 * 40];
+
+        let multigrid = Multigrid::smoothed_aggregation(&a, &AmgParameters::new()).unwrap();
+        assert!(multigrid.levels.len() > 2);
+
+        let standalone = system.multigrid_solve(&x0, &multigrid, 1e-16, 100);
+        assert!(standalone.converged, "{}", standalone.message);
+
+        let plain = system.conjugate_gradient_solve(&x0, &IdentityPreconditioner, 1e-16, 1000);
+        let preconditioned = system.conjugate_gradient_solve(&x0, &multigrid, 1e-16, 1000);
+        assert!(preconditioned.converged, "{}", preconditioned.message);
+        assert!(preconditioned.iters * 4 < plain.iters);
+    }
+
+    #[test]
+    fn test_solvers_on_csr_storage() {
+        let a = CsrMatrix::from(&laplacian_2d(20, 1.0));
+        let b = vec![1.0; 400];
+        let x0 = vec![0.0; 400];
+        let system = SparseSystem::new(&a, &b);
+        let ilu = Ilu0Preconditioner::new(&a).unwrap();
+
+        for result in [
+            system.gauss_seidel_solve(&x0, 1e-16, 5000),
+            system.bicgstab_solve(&x0, &ilu, 1e-16, 1000),
+        ] {
+            assert!(result.converged, "{}", result.message);
+        }
+    }
+
+    #[test]
+    fn test_max_iters_is_not_converged() {
+        let a = laplacian_1d(50);
+        let b = vec![1.0; 50];
+        let system = SparseSystem::new(&a, &b);
+        let result = system.gauss_seidel_solve(&vec![0.0; 50], 1e-20, 3);
+        assert!(!result.converged);
+        assert!(result.max_iters_reached);
+        assert_eq!(result.iters, 3);
+    }
+}
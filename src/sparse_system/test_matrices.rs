@@ -0,0 +1,48 @@
+use crate::sparse_system::sparse_matrix::SparseMatrix;
+
+/// Constant coefficient tridiagonal matrix, `lower` and `upper` next to the diagonal
+pub fn tridiagonal(n: usize, lower: f64, diagonal: f64, upper: f64) -> SparseMatrix {
+    let entries = (0..n)
+        .flat_map(|i| {
+            let mut row = vec![(i, i, diagonal)];
+            if i > 0 {
+                row.push((i, i - 1, lower));
+            }
+            if i + 1 < n {
+                row.push((i, i + 1, upper));
+            }
+            row
+        })
+        .collect();
+    SparseMatrix::from_entries(entries, n, n)
+}
+
+/// [-1 2 -1] stencil on a chain of n cells
+pub fn laplacian_1d(n: usize) -> SparseMatrix {
+    tridiagonal(n, -1.0, 2.0, -1.0)
+}
+
+/// 5-point stencil on an n x n grid. A positive `convection` adds upwind transport along the
+/// first axis, which makes the matrix non-symmetric.
+pub fn laplacian_2d(n: usize, convection: f64) -> SparseMatrix {
+    let id = |i: usize, j: usize| i * n + j;
+    let mut entries = Vec::with_capacity(5 * n * n);
+    for i in 0..n {
+        for j in 0..n {
+            entries.push((id(i, j), id(i, j), 4.0 + convection));
+            if i > 0 {
+                entries.push((id(i, j), id(i - 1, j), -1.0 - convection));
+            }
+            if i + 1 < n {
+                entries.push((id(i, j), id(i + 1, j), -1.0));
+            }
+            if j > 0 {
+                entries.push((id(i, j), id(i, j - 1), -1.0));
+            }
+            if j + 1 < n {
+                entries.push((id(i, j), id(i, j + 1), -1.0));
+            }
+        }
+    }
+    SparseMatrix::from_entries(entries, n * n, n * n)
+}